@@ -0,0 +1,255 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::ConfigFormat;
+use crate::error::WakerError;
+use crate::machines::Machines;
+
+/// Number of automatic backups kept when WAKER_KEEP_BACKUPS is not set
+pub const DEFAULT_KEEP: usize = 10;
+
+/// Environment variable overriding how many automatic backups are kept
+pub const KEEP_ENV: &str = "WAKER_KEEP_BACKUPS";
+
+/// How many automatic backups to keep, at least one
+pub fn keep_count() -> usize {
+    std::env::var(KEEP_ENV)
+        .ok()
+        .and_then(|keep| keep.trim().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_KEEP)
+        .max(1)
+}
+
+/// Directory the backups of a config file are kept in, "waker-backups" next to the config
+pub fn backups_dir(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("waker-backups")
+}
+
+/// Backups of a config file are named "<stem>-<UTC time>.<extension>"
+fn prefix(config_path: &Path) -> String {
+    let stem = config_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("waker");
+    format!("{}-", stem)
+}
+
+/// When the backup was taken, if the file name is that of a backup of this config. Anything
+/// else, such as a backup of "a-b.json" when looking for those of "a.json", or of "waker.toml"
+/// when looking for those of "waker.json", is None.
+fn backup_time(config_path: &Path, file_name: &str) -> Option<SystemTime> {
+    let suffix = format!(".{}", ConfigFormat::from_path(config_path).extension());
+    let time = file_name.strip_prefix(&prefix(config_path))?.strip_suffix(&suffix)?;
+    parse_timestamp(time)
+}
+
+/// UTC time as YYYYMMDD-HHMMSS-mmm, which sorts in chronological order
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, day_secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since 1970-01-01, Howard Hinnant's days_from_civil in reverse
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs / 60 % 60,
+        day_secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Reads back a timestamp written by timestamp()
+fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    let digits: String = text.chars().filter(|c| *c != '-').collect();
+    if digits.len() != 17 || !digits.chars().all(|c| c.is_ascii_digit()) || text.len() != 19 {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| digits[range].parse::<i64>().unwrap_or_default();
+    let (year, month, day) = (number(0..4), number(4..6), number(6..8));
+
+    // Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + number(8..10) * 3600 + number(10..12) * 60 + number(12..14);
+    let millis = secs.checked_mul(1000)?.checked_add(number(14..17))?;
+    if millis < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + std::time::Duration::from_millis(millis as u64))
+}
+
+/// Backups of a config file, oldest first
+pub fn list(config_path: &Path) -> Result<Vec<PathBuf>, WakerError> {
+    let dir = backups_dir(config_path);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut backups: Vec<PathBuf> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str());
+            name.and_then(|name| backup_time(config_path, name)).is_some()
+        })
+        .collect();
+    backups.sort();
+    Ok(backups)
+}
+
+/// Copies the config file into the backups directory, then removes the oldest backups so that
+/// only the last `keep` remain. Returns where the copy went.
+pub fn create(config_path: &Path, keep: usize) -> Result<PathBuf, WakerError> {
+    let dir = backups_dir(config_path);
+    std::fs::create_dir_all(&dir)?;
+    let extension = ConfigFormat::from_path(config_path).extension();
+    let prefix = prefix(config_path);
+
+    // Names have to keep sorting in the order backups were taken, even when several are taken
+    // within a millisecond or the clock went back, so never go below the latest one
+    let latest = list(config_path)?
+        .last()
+        .and_then(|latest| latest.file_name().and_then(|name| name.to_str()).and_then(|name| backup_time(config_path, name)));
+    let mut time = SystemTime::now();
+    if let Some(latest) = latest {
+        time = time.max(latest + std::time::Duration::from_millis(1));
+    }
+    let mut backup_path = dir.join(format!("{}{}.{}", prefix, timestamp(time), extension));
+    while backup_path.exists() {
+        time += std::time::Duration::from_millis(1);
+        backup_path = dir.join(format!("{}{}.{}", prefix, timestamp(time), extension));
+    }
+    std::fs::copy(config_path, &backup_path)?;
+
+    let backups = list(config_path)?;
+    if backups.len() > keep {
+        for old in &backups[..backups.len() - keep] {
+            std::fs::remove_file(old)?;
+        }
+    }
+    Ok(backup_path)
+}
+
+/// Finds the file to restore from: a path to any config file, the file name of a backup or its
+/// index in "waker backup list"
+pub fn find(config_path: &Path, source: &str) -> Result<PathBuf, WakerError> {
+    let path = PathBuf::from(source);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let backups = list(config_path)?;
+    if let Some(backup) = backups.iter().find(|backup| backup.file_name().and_then(|name| name.to_str()) == Some(source)) {
+        return Ok(backup.clone());
+    }
+    if let Some(backup) = source.parse::<usize>().ok().and_then(|index| backups.get(index)) {
+        return Ok(backup.clone());
+    }
+    Err(WakerError::Config(format!("No backup or file named \"{}\"", source)))
+}
+
+/// Replaces the config with the contents of another file, in the format of the config, after
+/// backing up the current one. Nothing is touched unless the file parses into a valid
+/// configuration. Returns the restored hosts and where the previous config went.
+pub fn restore(config_path: &Path, source: &Path, keep: usize) -> Result<(Machines, PathBuf), WakerError> {
+    let text = std::fs::read_to_string(source)?;
+    let machines = ConfigFormat::from_path(source).deserialize(&text).map_err(|what| match what {
+        WakerError::Config(what) => WakerError::Config(format!("{} is not a valid config: {}", source.display(), what)),
+        other => other,
+    })?;
+    let saved = create(config_path, keep)?;
+    machines.dump(config_path)?;
+    Ok((machines, saved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_sort() {
+        assert_eq!("19700101-000000-000", timestamp(UNIX_EPOCH));
+        let leap_day = UNIX_EPOCH + std::time::Duration::from_millis(1_709_210_096_789);
+        assert_eq!("20240229-123456-789", timestamp(leap_day));
+        assert_eq!(Some(leap_day), parse_timestamp("20240229-123456-789"));
+        assert_eq!(Some(UNIX_EPOCH), parse_timestamp("19700101-000000-000"));
+        assert_eq!(None, parse_timestamp("2024-02-29"));
+    }
+
+    #[test]
+    fn rotate_and_restore() {
+        let dir = std::env::temp_dir().join(format!("waker-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("waker.json");
+        let mut m = Machines::new();
+        m.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        m.dump(&config_path).unwrap();
+
+        let first = create(&config_path, 2).unwrap();
+        let second = create(&config_path, 2).unwrap();
+        let third = create(&config_path, 2).unwrap();
+        let backups = list(&config_path).unwrap();
+
+        Machines::new().dump(&config_path).unwrap();
+        let by_index = find(&config_path, "0").unwrap();
+        let (restored, saved) = restore(&config_path, &by_index, 2).unwrap();
+        let reloaded = Machines::from_file(&config_path).unwrap();
+
+        let garbage = dir.join("garbage.json");
+        std::fs::write(&garbage, "{ not json").unwrap();
+        let rejected = restore(&config_path, &garbage, 2);
+        let untouched = Machines::from_file(&config_path).unwrap();
+        let backups_after = list(&config_path).unwrap();
+        let missing = find(&config_path, "nope");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(vec![second.clone(), third.clone()], backups);
+        assert!(!first.exists());
+        assert_eq!(second, by_index);
+        assert_eq!(1, restored.list.len());
+        assert_eq!(vec![third, saved], backups_after); // Nothing saved for the rejected file
+        assert_eq!("nas", reloaded.list[0].name);
+        assert!(matches!(rejected, Err(WakerError::Config(_))));
+        assert_eq!(1, untouched.list.len());
+        assert!(matches!(missing, Err(WakerError::Config(_))));
+    }
+
+    #[test]
+    fn backups_of_other_configs_are_left_alone() {
+        let dir = std::env::temp_dir().join(format!("waker-backup-names-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("a.json");
+        let others = [dir.join("a-b.json"), dir.join("a.toml")];
+        for path in others.iter().chain([&config_path]) {
+            Machines::new().dump(path).unwrap();
+        }
+        let other_backups: Vec<PathBuf> = others.iter().map(|other| create(other, 1).unwrap()).collect();
+        let own = create(&config_path, 1).unwrap();
+        let own_again = create(&config_path, 1).unwrap();
+        let backups = list(&config_path).unwrap();
+        let others_kept = other_backups.iter().all(|backup| backup.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(vec![own_again], backups);
+        assert!(!own.exists());
+        assert!(others_kept);
+        assert_eq!(None, backup_time(&config_path, "a-20240229-123456-789.toml"));
+        assert_eq!(None, backup_time(&config_path, "a-b-20240229-123456-789.json"));
+        assert!(backup_time(&config_path, "a-20240229-123456-789.json").is_some());
+    }
+}
@@ -1,7 +1,17 @@
 // use std::{path::PathBuf, str::FromStr};
 
 use crate::{BackupMode, RunMode, WakeMode};
-use clap::{App, Arg, ArgMatches};
+use waker::commands::{AddArgs, GroupArgs, SetArgs, UNSETTABLE_FIELDS};
+use waker::config::{self, ConfigCommand, ConfigFormat};
+use waker::export::{self, ExportArgs};
+use waker::import::{ConflictPolicy, Format, ImportArgs, FORMAT_NAMES};
+use waker::machines::Selection;
+use waker::pacing::{self, Pacing};
+use waker::packet::MagicPacket;
+use waker::status::{ProbeOptions, WaitOptions};
+use waker::wake::WakeOptions;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use std::time::Duration;
 
 // use crate::main::RunMode;
 
@@ -16,19 +26,114 @@ use clap::{App, Arg, ArgMatches};
 
 // This is essentially and abstraction of clap
 /// Parses command line arguments and returns a RunMode enum containing desired run mode.
-pub fn get_runmode() -> RunMode {
-    let matches = get_cli_matches();
+pub fn get_runmode(matches: &ArgMatches) -> RunMode {
+    match matches.subcommand() {
+        Some(("add", sub)) => {
+            return RunMode::Add(AddArgs {
+                name: value_string(sub, "name"),
+                macs: values_strings(sub, "mac"),
+                ips: values_strings(sub, "ip"),
+                password: value_string(sub, "password"),
+                tags: values_strings(sub, "tag"),
+            });
+        }
+        Some(("remove", sub)) => return RunMode::Remove(value_string(sub, "name")),
+        Some(("rename", sub)) => return RunMode::Rename(value_string(sub, "old"), value_string(sub, "new")),
+        Some(("set", sub)) => {
+            return RunMode::Set(SetArgs {
+                name: value_string(sub, "name"),
+                add_macs: values_strings(sub, "add_mac"),
+                remove_macs: values_strings(sub, "remove_mac"),
+                add_ips: values_strings(sub, "add_ip"),
+                remove_ips: values_strings(sub, "remove_ip"),
+                password: value_string(sub, "password"),
+                ethernet: value_string(sub, "ethernet"),
+                broadcast: value_string(sub, "broadcast"),
+                ipv6: value_string(sub, "ipv6"),
+                prefix: value_string(sub, "prefix"),
+                port: value_string(sub, "port"),
+                interface: value_string(sub, "interface"),
+                source: value_string(sub, "source"),
+                repeat: value_string(sub, "repeat"),
+                interval: value_string(sub, "interval"),
+                burst: value_string(sub, "burst"),
+                unset: values_strings(sub, "unset"),
+                add_tags: values_strings(sub, "add_tag"),
+                remove_tags: values_strings(sub, "remove_tag"),
+            });
+        }
+        Some(("import", sub)) => {
+            let policy = if sub.is_present("overwrite") {
+                Some(ConflictPolicy::Overwrite)
+            } else if sub.is_present("skip_conflicts") {
+                Some(ConflictPolicy::Skip)
+            } else if sub.is_present("all") {
+                Some(ConflictPolicy::Fail)
+            } else {
+                None
+            };
+            return RunMode::Import(ImportArgs {
+                path: value_string(sub, "file").map(|file| file.into()),
+                format: sub.value_of("format").and_then(Format::from_name).unwrap_or(Format::Waker),
+                policy,
+            });
+        }
+        Some(("config", sub)) => {
+            if let Some(("convert", convert)) = sub.subcommand() {
+                let format = convert.value_of("format").and_then(ConfigFormat::from_name).unwrap_or(ConfigFormat::Json);
+                return RunMode::Config(ConfigCommand::Convert(format));
+            }
+        }
+        Some(("backup", sub)) => {
+            if let Some(("list", _)) = sub.subcommand() {
+                return RunMode::Backup(BackupMode::List);
+            }
+        }
+        Some(("undo", _)) => return RunMode::Undo,
+        Some(("redo", _)) => return RunMode::Redo,
+        Some(("history", _)) => return RunMode::History,
+        Some(("restore", sub)) => return RunMode::Restore(value_string(sub, "backup").unwrap_or_default()),
+        Some(("export", sub)) => {
+            return RunMode::Export(ExportArgs {
+                format: sub.value_of("format").and_then(export::Format::from_name).unwrap_or(export::Format::Csv),
+                output: value_string(sub, "output").map(|file| file.into()),
+            });
+        }
+        Some(("group", sub)) => {
+            return RunMode::Group(GroupArgs {
+                name: value_string(sub, "name"),
+                add: values_strings(sub, "add"),
+                remove: values_strings(sub, "remove"),
+                delete: sub.is_present("delete"),
+            });
+        }
+        _ => {}
+    }
     if matches.is_present("add") {
-        return RunMode::Add;
+        return RunMode::Add(AddArgs {
+            password: value_string(matches, "password"),
+            ..Default::default()
+        });
+    }
+    if let Some(("wake", sub)) = matches.subcommand() {
+        // Wake options may be given before or after "wake", the ones after it win
+        let selection = Selection {
+            targets: values_strings(sub, "targets"),
+            tags: values_strings(sub, "tag"),
+            groups: values_strings(sub, "group"),
+        };
+        return RunMode::Wake(WakeMode::Targets(selection), get_wake_options(&[sub, matches]));
     }
+    let probe_options = get_probe_options(&[matches]);
+    let wake_options = get_wake_options(&[matches]);
     if matches.is_present("all") {
-        return RunMode::Wake(WakeMode::WakeAll);
+        return RunMode::Wake(WakeMode::WakeAll, wake_options);
     }
     if matches.is_present("edit") {
         return RunMode::Edit;
     }
     if matches.is_present("list") {
-        return RunMode::List;
+        return RunMode::List(probe_options);
     }
     if matches.is_present("backup") {
         let path_str = matches.value_of("backup").unwrap();
@@ -37,7 +142,282 @@ pub fn get_runmode() -> RunMode {
     if matches.is_present("print_config") {
         return RunMode::Backup(BackupMode::ToStdout);
     }
-    return RunMode::Wake(WakeMode::WakeSome);
+    if matches.is_present("targets") {
+        let selection = Selection {
+            targets: values_strings(matches, "targets"),
+            ..Default::default()
+        };
+        return RunMode::Wake(WakeMode::Targets(selection), wake_options);
+    }
+    return RunMode::Wake(WakeMode::WakeSome, wake_options);
+}
+
+/// Builds the options shared by every kind of wake, taking each value from the first matches
+/// that has it
+fn get_wake_options(layers: &[&ArgMatches]) -> WakeOptions {
+    return WakeOptions {
+        ethernet: layered_value(layers, "ethernet").map(|i| i.to_string()),
+        probe: get_probe_options(layers),
+        wait: layered_value(layers, "wait").map(|timeout| WaitOptions {
+            timeout: Duration::from_secs(timeout.parse().unwrap_or_default()),
+            interval: Duration::from_secs(layered_value(layers, "retry_interval").unwrap_or("5").parse().unwrap_or(5)),
+        }),
+        exec: layered_value(layers, "exec").map(|command| command.to_string()),
+        pacing: Pacing {
+            repeat: layered_value(layers, "repeat").and_then(|repeat| pacing::parse_repeat(repeat).ok()),
+            interval: layered_value(layers, "interval").and_then(|interval| interval.parse().ok()),
+            burst: layers.iter().any(|matches| matches.is_present("burst")).then_some(true),
+        },
+        default_pacing: Pacing::default(),
+    };
+}
+
+/// Value of an argument from the first matches that has it
+fn layered_value<'a>(layers: &[&'a ArgMatches], name: &str) -> Option<&'a str> {
+    layers.iter().find_map(|matches| matches.value_of(name))
+}
+
+/// Owned copy of a single valued argument
+fn value_string(matches: &ArgMatches, name: &str) -> Option<String> {
+    matches.value_of(name).map(|value| value.to_string())
+}
+
+/// Owned copies of every occurrence of an argument
+fn values_strings(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|values| values.map(|value| value.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Builds the liveness probe options, the values have already been validated by clap
+fn get_probe_options(layers: &[&ArgMatches]) -> ProbeOptions {
+    let mut options = ProbeOptions::default();
+    if let Some(timeout) = layered_value(layers, "probe_timeout") {
+        options.timeout = Duration::from_millis(timeout.parse().unwrap_or_default());
+    }
+    if let Some(ports) = layered_value(layers, "probe_ports") {
+        options.tcp_ports = parse_ports(ports).unwrap_or_default();
+    }
+    return options;
+}
+
+/// Parses a comma separated list of ports
+fn parse_ports(ports: &str) -> Result<Vec<u16>, String> {
+    ports
+        .split(',')
+        .filter(|port| !port.trim().is_empty())
+        .map(|port| port.trim().parse::<u16>().map_err(|_| format!("Invalid port: {}", port)))
+        .collect()
+}
+
+/// Options that tune how hosts are woken, shared by plain waker and "waker wake"
+fn wake_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        Arg::new("ethernet")
+            .long("ethernet")
+            .help("Send magic packets as raw Ethernet frames on this interface")
+            .value_name("INTERFACE"),
+        Arg::new("wait")
+            .long("wait")
+            .validator(|t| t.parse::<u64>())
+            .help("Keep waking until the hosts answer, failing after this many seconds")
+            .value_name("SECONDS"),
+        Arg::new("retry_interval")
+            .long("retry-interval")
+            .requires("wait")
+            .validator(|t| t.parse::<u64>())
+            .help("How often to resend magic packets while waiting, defaults to 5")
+            .value_name("SECONDS"),
+        Arg::new("exec")
+            .long("exec")
+            .requires("wait")
+            .help("Shell command to run once a host is up, with WAKER_HOST and WAKER_IP set")
+            .value_name("COMMAND"),
+        Arg::new("probe_ports")
+            .long("probe-ports")
+            .validator(parse_ports)
+            .help("Comma separated TCP ports to probe when ICMP is unavailable")
+            .value_name("PORTS"),
+        Arg::new("probe_timeout")
+            .long("probe-timeout")
+            .validator(|t| t.parse::<u64>())
+            .help("How long to wait for each probe")
+            .value_name("MILLISECONDS"),
+        Arg::new("repeat")
+            .long("repeat")
+            .validator(pacing::parse_repeat)
+            .help("Send every magic packet this many times, overriding the config")
+            .value_name("COUNT"),
+        Arg::new("interval")
+            .long("interval")
+            .validator(|t| t.parse::<u64>())
+            .help("Pause between repeated magic packets, defaults to 100")
+            .value_name("MILLISECONDS"),
+        Arg::new("burst")
+            .long("burst")
+            .help("Also send to every broadcast and unicast address of each host"),
+    ]
+}
+
+/// Repeatable option taking a single value per occurrence
+fn multi_arg<'a>(name: &'a str, long: &'a str, value_name: &'a str, help: &'a str) -> Arg<'a> {
+    Arg::new(name)
+        .long(long)
+        .takes_value(true)
+        .multiple_occurrences(true)
+        .value_name(value_name)
+        .help(help)
+}
+
+/// Single valued option
+fn value_arg<'a>(name: &'a str, value_name: &'a str, help: &'a str) -> Arg<'a> {
+    Arg::new(name).long(name).takes_value(true).value_name(value_name).help(help)
+}
+
+/// Non-interactive host management, anything required that is left out is prompted for
+fn host_subcommands<'a>() -> Vec<App<'a>> {
+    vec![
+        App::new("wake")
+            .about("Wake hosts by name, shell glob, list index or MAC address, without prompting")
+            .arg(
+                Arg::new("targets")
+                    .value_name("TARGET")
+                    .required_unless_present_any(["tag", "group"])
+                    .multiple_occurrences(true),
+            )
+            .arg(multi_arg("tag", "tag", "TAG", "Also wake every host with this tag"))
+            .arg(multi_arg("group", "group", "GROUP", "Also wake every member of this group"))
+            .args(wake_args()),
+        App::new("add")
+            .about("Add a host, prompting for anything left out")
+            .arg(value_arg("name", "NAME", "Name of the new host"))
+            .arg(multi_arg("mac", "mac", "MAC", "MAC address, can be given more than once"))
+            .arg(multi_arg("ip", "ip", "IP", "IP address, can be given more than once"))
+            .arg(
+                value_arg("password", "PASSWORD", "SecureOn password")
+                    .validator(|p| MagicPacket::parse_password(p).map(|_| ()).map_err(|e| e.to_string())),
+            )
+            .arg(multi_arg("tag", "tag", "TAG", "Tag, can be given more than once")),
+        App::new("remove")
+            .about("Remove a host, prompting for which one if no name is given")
+            .arg(Arg::new("name").value_name("NAME")),
+        App::new("rename")
+            .about("Rename a host")
+            .arg(Arg::new("old").value_name("OLD"))
+            .arg(Arg::new("new").value_name("NEW")),
+        App::new("set")
+            .about("Change fields of a host, edits it interactively if no changes are given")
+            .arg(Arg::new("name").value_name("NAME"))
+            .arg(multi_arg("add_mac", "add-mac", "MAC", "Add a MAC address"))
+            .arg(multi_arg("remove_mac", "remove-mac", "MAC", "Remove a MAC address"))
+            .arg(multi_arg("add_ip", "add-ip", "IP", "Add an IP address"))
+            .arg(multi_arg("remove_ip", "remove-ip", "IP", "Remove an IP address"))
+            .arg(value_arg("password", "PASSWORD", "SecureOn password"))
+            .arg(value_arg("ethernet", "INTERFACE", "Wake with raw Ethernet frames on this interface"))
+            .arg(value_arg("broadcast", "ADDRESS", "Broadcast address to send to"))
+            .arg(
+                value_arg("ipv6", "ADDRESS", "Send over IPv6 to this address, ff02::1 if none is given")
+                    .min_values(0)
+                    .default_missing_value("ff02::1"),
+            )
+            .arg(value_arg("prefix", "LENGTH", "Prefix length used to derive a directed broadcast"))
+            .arg(value_arg("port", "PORT", "UDP port to send to"))
+            .arg(value_arg("interface", "INTERFACE", "Interface to bind the UDP socket to"))
+            .arg(value_arg("source", "ADDRESS", "Source address to bind the UDP socket to"))
+            .arg(value_arg("repeat", "COUNT", "Times every magic packet is sent"))
+            .arg(value_arg("interval", "MILLISECONDS", "Pause between repeated magic packets"))
+            .arg(
+                value_arg("burst", "ON", "Also send to every broadcast and unicast address of the host")
+                    .possible_values(["on", "off"]),
+            )
+            .arg(
+                multi_arg("unset", "unset", "FIELD", "Clear an optional field")
+                    .possible_values(UNSETTABLE_FIELDS),
+            )
+            .arg(multi_arg("add_tag", "add-tag", "TAG", "Add a tag"))
+            .arg(multi_arg("remove_tag", "remove-tag", "TAG", "Remove a tag")),
+        App::new("import")
+            .about("Import hosts from another config file, picking them interactively unless told otherwise")
+            .arg(
+                Arg::new("file")
+                    .value_name("FILE")
+                    .required_unless_present("format")
+                    .help("File to import, defaults to the usual location of a foreign format"),
+            )
+            .arg(
+                value_arg("format", "FORMAT", "Import from /etc/ethers, dnsmasq or dhcpd leases or the ARP table")
+                    .possible_values(FORMAT_NAMES),
+            )
+            .arg(
+                Arg::new("all")
+                    .long("all")
+                    .help("Import every new host, failing if any host conflicts with a configured one"),
+            )
+            .arg(
+                Arg::new("skip_conflicts")
+                    .long("skip-conflicts")
+                    .conflicts_with("overwrite")
+                    .help("Import every new host, keeping configured hosts that conflict"),
+            )
+            .arg(
+                Arg::new("overwrite")
+                    .long("overwrite")
+                    .help("Import every new host, replacing configured hosts that conflict"),
+            ),
+        App::new("backup")
+            .about("Automatic backups, taken before every change to the config")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(App::new("list").about("List the backups, oldest first")),
+        App::new("restore")
+            .about("Replace the config with a backup or another config file, once it is found valid")
+            .arg(
+                Arg::new("backup")
+                    .value_name("BACKUP")
+                    .required(true)
+                    .help("Name or index from \"waker backup list\", or the path to a config file"),
+            ),
+        App::new("undo").about("Revert the last change made to the config"),
+        App::new("redo").about("Replay the last change that was undone"),
+        App::new("history").about("List the changes made to the config, oldest first"),
+        App::new("config")
+            .about("Operations on the config file itself")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                App::new("convert")
+                    .about("Rewrite the config file as JSON, TOML or YAML, replacing the old file")
+                    .arg(
+                        Arg::new("format")
+                            .value_name("FORMAT")
+                            .required(true)
+                            .possible_values(config::FORMAT_NAMES),
+                    ),
+            ),
+        App::new("export")
+            .about("Render the configured hosts for inventory and DHCP tooling")
+            .arg(
+                value_arg("format", "FORMAT", "Format to export to")
+                    .required(true)
+                    .possible_values(export::FORMAT_NAMES),
+            )
+            .arg(value_arg("output", "FILE", "Write to this file instead of stdout").short('o')),
+        App::new("group")
+            .about("List groups, or change the members of one")
+            .arg(Arg::new("name").value_name("GROUP"))
+            .arg(multi_arg("add", "add", "HOST", "Add a host, creating the group if needed").requires("name"))
+            .arg(multi_arg("remove", "remove", "HOST", "Take a host out of the group").requires("name"))
+            .arg(
+                Arg::new("delete")
+                    .long("delete")
+                    .requires("name")
+                    .help("Delete the group, its hosts are kept"),
+            ),
+    ]
+}
+
+/// Config file given with --config, before or after a subcommand
+pub fn get_config_file(matches: &ArgMatches) -> Option<String> {
+    return value_string(matches, "config_file");
 }
 
 pub fn get_cli_matches() -> ArgMatches {
@@ -49,12 +429,32 @@ pub fn get_cli_matches() -> ArgMatches {
         .version(env!("CARGO_PKG_VERSION"))
         .author("Imbus64")
         .about("Utility for sending magic packets to configured machines.")
+        .subcommands(host_subcommands())
+        .args(wake_args())
+        .mut_arg("ethernet", |arg| arg.conflicts_with_all(&["add", "edit", "list", "backup", "print_config"]))
+        .mut_arg("wait", |arg| arg.conflicts_with_all(&["add", "edit", "list", "backup", "print_config"]))
+        .arg(
+            Arg::new("config_file")
+                .long("config")
+                .global(true)
+                .takes_value(true)
+                .help("Config file to use instead of the default one, also read from WAKER_CONFIG")
+                .value_name("FILE"),
+        )
         .arg(
             Arg::new("add")
                 .short('a')
                 .long("add")
                 .help("Add a new host"),
         )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .requires("add")
+                .validator(|p| MagicPacket::parse_password(p).map(|_| ()).map_err(|e| e.to_string()))
+                .help("SecureOn password for the host being added")
+                .value_name("PASSWORD"),
+        )
         .arg(
             Arg::new("all")
                 .long("all")
@@ -87,7 +487,9 @@ pub fn get_cli_matches() -> ArgMatches {
                 .help("Print contents of configuration file to stdout"),
         )
         .arg(
-            Arg::new("MAC ADDRESSES")
+            Arg::new("targets")
+                .value_name("TARGETS")
+                .help("Hosts to wake by name, glob, index or MAC, same as \"waker wake\"")
                 .conflicts_with_all(&["all", "list", "edit", "backup"])
                 .multiple_occurrences(true),
         )
@@ -95,3 +497,18 @@ pub fn get_cli_matches() -> ArgMatches {
         // .long("asdf")
         .get_matches();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use waker::machines::RESERVED_NAMES;
+
+    #[test]
+    fn subcommands_are_reserved_names() {
+        let subcommands = host_subcommands();
+        let mut names: Vec<&str> = subcommands.iter().map(|app| app.get_name()).collect();
+        names.push("help");
+        names.sort_unstable();
+        assert_eq!(RESERVED_NAMES.to_vec(), names);
+    }
+}
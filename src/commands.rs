@@ -0,0 +1,445 @@
+use std::net::IpAddr;
+
+use crate::error::WakerError;
+use crate::machines::Machines;
+use crate::mac::MacAddress;
+use crate::packet::MagicPacket;
+use crate::pacing;
+use crate::sanitizers::{self, Mode};
+
+// Non-interactive host management, as used by the add, remove, set and rename subcommands.
+// Everything given on the command line is validated with the strict sanitizers before the
+// config is touched, so a typo in a script fails loudly instead of storing garbage.
+
+/// Arguments to "waker add". Anything left out is prompted for interactively.
+#[derive(Default)]
+pub struct AddArgs {
+    pub name: Option<String>,
+    pub macs: Vec<String>,
+    pub ips: Vec<String>,
+    pub password: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl AddArgs {
+    /// Whether enough was given to add the host without asking anything
+    pub fn is_complete(&self) -> bool {
+        self.name.is_some() && !self.macs.is_empty()
+    }
+}
+
+/// Arguments to "waker set", describing changes to a single host
+#[derive(Default)]
+pub struct SetArgs {
+    pub name: Option<String>,
+    pub add_macs: Vec<String>,
+    pub remove_macs: Vec<String>,
+    pub add_ips: Vec<String>,
+    pub remove_ips: Vec<String>,
+    pub password: Option<String>,
+    pub ethernet: Option<String>,
+    pub broadcast: Option<String>,
+    pub ipv6: Option<String>,
+    pub prefix: Option<String>,
+    pub port: Option<String>,
+    pub interface: Option<String>,
+    pub source: Option<String>,
+    pub repeat: Option<String>,
+    pub interval: Option<String>,
+    pub burst: Option<String>, // "on" or "off"
+    pub unset: Vec<String>,    // Optional fields to clear
+    pub add_tags: Vec<String>,
+    pub remove_tags: Vec<String>,
+}
+
+/// Arguments to "waker group". Without any changes the group is listed instead.
+#[derive(Default)]
+pub struct GroupArgs {
+    pub name: Option<String>,
+    pub add: Vec<String>,    // Host names to add
+    pub remove: Vec<String>, // Host names to take out
+    pub delete: bool,        // Delete the whole group
+}
+
+impl GroupArgs {
+    /// Whether any change was asked for at all
+    pub fn has_changes(&self) -> bool {
+        self.delete || !self.add.is_empty() || !self.remove.is_empty()
+    }
+}
+
+impl SetArgs {
+    /// Whether any change was asked for at all
+    pub fn has_changes(&self) -> bool {
+        !(self.add_macs.is_empty()
+            && self.remove_macs.is_empty()
+            && self.add_ips.is_empty()
+            && self.remove_ips.is_empty()
+            && self.unset.is_empty()
+            && self.add_tags.is_empty()
+            && self.remove_tags.is_empty()
+            && self.password.is_none()
+            && self.ethernet.is_none()
+            && self.broadcast.is_none()
+            && self.ipv6.is_none()
+            && self.prefix.is_none()
+            && self.port.is_none()
+            && self.interface.is_none()
+            && self.source.is_none()
+            && self.repeat.is_none()
+            && self.interval.is_none()
+            && self.burst.is_none())
+    }
+}
+
+/// Fields that "waker set --unset" can clear
+pub const UNSETTABLE_FIELDS: [&str; 11] = [
+    "password", "ethernet", "broadcast", "ipv6", "prefix", "port", "interface", "source", "repeat", "interval", "burst",
+];
+
+/// Parses a MAC address given on the command line
+pub fn parse_mac_arg(mac_str: &str) -> Result<MacAddress, WakerError> {
+    sanitizers::parse_mac(mac_str, Mode::Strict)
+}
+
+/// Parses an IP address given on the command line
+pub fn parse_ip_arg(ip_str: &str) -> Result<IpAddr, WakerError> {
+    sanitizers::parse_ip(ip_str, Mode::Strict)
+}
+
+/// Checks a tag given on the command line, tags are listed comma separated so they can not hold commas
+pub fn parse_tag(tag: &str) -> Result<String, WakerError> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.contains(',') {
+        return Err(WakerError::Parse(format!("Invalid tag: \"{}\"", tag)));
+    }
+    Ok(tag.to_string())
+}
+
+/// Builds a new host from fully specified add arguments and appends it to the list
+pub fn add(machines: &mut Machines, args: &AddArgs) -> Result<(), WakerError> {
+    let name = match &args.name {
+        Some(name) if !name.trim().is_empty() => name.trim(),
+        _ => return Err(WakerError::Parse("A host needs a name".to_string())),
+    };
+    Machines::check_name(name)?;
+    if machines.find(name).is_some() {
+        return Err(WakerError::Config(format!("A host named \"{}\" already exists", name)));
+    }
+    if args.macs.is_empty() {
+        return Err(WakerError::Parse(format!("{} needs at least one MAC address", name)));
+    }
+    let macs: Vec<MacAddress> = args.macs.iter().map(|mac| parse_mac_arg(mac)).collect::<Result<_, _>>()?;
+    let ips: Vec<IpAddr> = args.ips.iter().map(|ip| parse_ip_arg(ip)).collect::<Result<_, _>>()?;
+    if let Some(password) = &args.password {
+        MagicPacket::parse_password(password)?;
+    }
+    let mut tags: Vec<String> = Vec::new();
+    for tag in &args.tags {
+        let tag = parse_tag(tag)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    let host = machines.add(name, macs[0], ips.first().copied());
+    host.macs.extend(&macs[1..]);
+    host.ips.extend(ips.iter().skip(1));
+    host.password = args.password.clone();
+    host.tags = tags;
+    Ok(())
+}
+
+/// Applies the changes described by the set arguments to the named host.
+/// Nothing is changed unless every argument is valid.
+pub fn set(machines: &mut Machines, args: &SetArgs) -> Result<(), WakerError> {
+    let name = args.name.as_deref().unwrap_or_default();
+    let mut host = machines
+        .find(name)
+        .ok_or_else(|| WakerError::Config(format!("No host named \"{}\"", name)))?
+        .clone();
+
+    for mac in &args.remove_macs {
+        let mac = parse_mac_arg(mac)?;
+        let before = host.macs.len();
+        host.macs.retain(|m| *m != mac);
+        if host.macs.len() == before {
+            return Err(WakerError::Config(format!("{} has no MAC address {}", host.name, mac)));
+        }
+    }
+    for mac in &args.add_macs {
+        let mac = parse_mac_arg(mac)?;
+        if !host.macs.contains(&mac) {
+            host.macs.push(mac);
+        }
+    }
+    if host.macs.is_empty() {
+        return Err(WakerError::Config(format!("{} needs at least one MAC address", host.name)));
+    }
+    for ip in &args.remove_ips {
+        let ip = parse_ip_arg(ip)?;
+        let before = host.ips.len();
+        host.ips.retain(|i| *i != ip);
+        if host.ips.len() == before {
+            return Err(WakerError::Config(format!("{} has no IP address {}", host.name, ip)));
+        }
+    }
+    for ip in &args.add_ips {
+        let ip = parse_ip_arg(ip)?;
+        if !host.ips.contains(&ip) {
+            host.ips.push(ip);
+        }
+    }
+    for tag in &args.remove_tags {
+        let tag = parse_tag(tag)?;
+        let before = host.tags.len();
+        host.tags.retain(|t| *t != tag);
+        if host.tags.len() == before {
+            return Err(WakerError::Config(format!("{} is not tagged {}", host.name, tag)));
+        }
+    }
+    for tag in &args.add_tags {
+        let tag = parse_tag(tag)?;
+        if !host.tags.contains(&tag) {
+            host.tags.push(tag);
+        }
+    }
+
+    for field in &args.unset {
+        match field.as_str() {
+            "password" => host.password = None,
+            "ethernet" => host.ethernet = None,
+            "broadcast" => host.broadcast = None,
+            "ipv6" => host.ipv6 = None,
+            "prefix" => host.prefix = None,
+            "port" => host.port = None,
+            "interface" => host.interface = None,
+            "source" => host.source = None,
+            "repeat" => host.repeat = None,
+            "interval" => host.interval = None,
+            "burst" => host.burst = None,
+            _ => return Err(WakerError::Parse(format!("Unknown field: {}", field))),
+        }
+    }
+    if let Some(password) = &args.password {
+        MagicPacket::parse_password(password)?;
+        host.password = Some(password.clone());
+    }
+    if let Some(ethernet) = &args.ethernet {
+        host.ethernet = Some(ethernet.clone());
+    }
+    if let Some(broadcast) = &args.broadcast {
+        host.broadcast = Some(sanitizers::parse_ipv4(broadcast, Mode::Strict)?);
+    }
+    if let Some(ipv6) = &args.ipv6 {
+        host.ipv6 = Some(sanitizers::parse_ipv6(ipv6, Mode::Strict)?);
+    }
+    if let Some(prefix) = &args.prefix {
+        match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= 32 => host.prefix = Some(prefix),
+            _ => return Err(WakerError::Parse(format!("Invalid prefix length: {}", prefix))),
+        }
+    }
+    if let Some(port) = &args.port {
+        host.port = Some(port.parse().map_err(|_| WakerError::Parse(format!("Invalid port: {}", port)))?);
+    }
+    if let Some(interface) = &args.interface {
+        host.interface = Some(interface.clone());
+    }
+    if let Some(source) = &args.source {
+        host.source = Some(sanitizers::parse_ipv4(source, Mode::Strict)?);
+    }
+    if let Some(repeat) = &args.repeat {
+        host.repeat = Some(pacing::parse_repeat(repeat).map_err(WakerError::Parse)?);
+    }
+    if let Some(interval) = &args.interval {
+        host.interval = Some(interval.parse().map_err(|_| WakerError::Parse(format!("Invalid interval: {}", interval)))?);
+    }
+    match args.burst.as_deref() {
+        Some("on") => host.burst = Some(true),
+        Some("off") => host.burst = Some(false),
+        Some(burst) => return Err(WakerError::Parse(format!("Burst is either on or off, not {}", burst))),
+        None => {}
+    }
+
+    if let Some(existing) = machines.find_mut(name) {
+        *existing = host;
+    }
+    Ok(())
+}
+
+/// Applies the changes described by the group arguments.
+/// Nothing is changed unless every change can be made.
+pub fn group(machines: &mut Machines, args: &GroupArgs) -> Result<(), WakerError> {
+    let name = args.name.as_deref().unwrap_or_default();
+    let before = machines.groups.clone();
+    let result = apply_group(machines, name, args);
+    if result.is_err() {
+        machines.groups = before;
+    }
+    result
+}
+
+fn apply_group(machines: &mut Machines, name: &str, args: &GroupArgs) -> Result<(), WakerError> {
+    for host in &args.add {
+        machines.group_add(name, host)?;
+    }
+    for host in &args.remove {
+        machines.group_remove(name, host)?;
+    }
+    if args.delete {
+        machines.group_delete(name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::demo_machines;
+
+    #[test]
+    fn add_validates() {
+        let mut m = demo_machines();
+        let args = AddArgs {
+            name: Some("Demo_Machine".to_string()),
+            macs: vec!["aa:bb:cc:dd:ee:ff".to_string()],
+            ips: vec!["10.0.0.1".to_string()],
+            password: None,
+            tags: vec!["lab".to_string()],
+        };
+        add(&mut m, &args).unwrap();
+        assert_eq!(5, m.list.len());
+        assert_eq!("AA:BB:CC:DD:EE:FF", m.list[4].macs[0].to_string());
+
+        let duplicate = AddArgs {
+            name: Some("Demo_Machine".to_string()),
+            macs: vec!["aa:bb:cc:dd:ee:00".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(add(&mut m, &duplicate), Err(WakerError::Config(_))));
+        let subcommand = AddArgs { name: Some("remove".to_string()), ..duplicate };
+        assert!(matches!(add(&mut m, &subcommand), Err(WakerError::Config(_))));
+
+        let bad_mac = AddArgs {
+            name: Some("Other_Machine".to_string()),
+            macs: vec!["aa:bb:cc:dd:ee".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(add(&mut m, &bad_mac), Err(WakerError::Parse(_))));
+
+        let bad_password = AddArgs {
+            name: Some("Other_Machine".to_string()),
+            macs: vec!["aa:bb:cc:dd:ee:00".to_string()],
+            password: Some("nope".to_string()),
+            ..Default::default()
+        };
+        assert!(add(&mut m, &bad_password).is_err());
+        assert_eq!(5, m.list.len());
+        assert!(bad_password.is_complete());
+        assert!(!AddArgs::default().is_complete());
+    }
+
+    #[test]
+    fn set_changes_host() {
+        let mut m = demo_machines();
+        let args = SetArgs {
+            name: Some("directed".to_string()),
+            add_macs: vec!["00-11-22-33-44-55".to_string()],
+            remove_ips: vec!["10.1.2.3".to_string()],
+            add_ips: vec!["10.0.0.2".to_string()],
+            port: Some("7".to_string()),
+            prefix: Some("24".to_string()),
+            repeat: Some("3".to_string()),
+            burst: Some("on".to_string()),
+            ipv6: Some("ff02::1".to_string()),
+            ..Default::default()
+        };
+        set(&mut m, &args).unwrap();
+        let host = &m.list[1];
+        assert_eq!(2, host.macs.len());
+        assert_eq!(vec!["10.0.0.2".parse::<IpAddr>().unwrap()], host.ips);
+        assert_eq!("10.0.0.255:7", host.destination().to_string());
+        assert_eq!((Some(3), Some(true)), (host.repeat, host.burst));
+        assert_eq!(Some(crate::packet::ALL_NODES), host.ipv6);
+
+        let unset = SetArgs {
+            name: Some("directed".to_string()),
+            unset: vec!["port".to_string(), "prefix".to_string(), "repeat".to_string(), "ipv6".to_string()],
+            burst: Some("off".to_string()),
+            ..Default::default()
+        };
+        set(&mut m, &unset).unwrap();
+        assert_eq!("255.255.255.255:9", m.list[1].destination().to_string());
+        assert_eq!((None, Some(false)), (m.list[1].repeat, m.list[1].burst));
+        assert_eq!(None, m.list[1].ipv6);
+
+        let never = SetArgs { name: Some("directed".to_string()), repeat: Some("0".to_string()), ..Default::default() };
+        assert!(matches!(set(&mut m, &never), Err(WakerError::Parse(_))));
+        let zoned = SetArgs { name: Some("directed".to_string()), ipv6: Some("fe80::1%eth0".to_string()), ..Default::default() };
+        assert!(matches!(set(&mut m, &zoned), Err(WakerError::Parse(_))));
+    }
+
+    #[test]
+    fn tags_and_groups() {
+        let mut m = demo_machines();
+        assert_eq!(vec!["lab"], m.list[0].tags);
+        let args = SetArgs {
+            name: Some("plain".to_string()),
+            add_tags: vec!["build".to_string()],
+            remove_tags: vec!["lab".to_string()],
+            ..Default::default()
+        };
+        set(&mut m, &args).unwrap();
+        assert_eq!(vec!["build"], m.list[0].tags);
+        let bad_tag = SetArgs {
+            name: Some("plain".to_string()),
+            add_tags: vec!["a,b".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(set(&mut m, &bad_tag), Err(WakerError::Parse(_))));
+
+        let args = GroupArgs {
+            name: Some("rack2".to_string()),
+            add: vec!["plain".to_string()],
+            ..Default::default()
+        };
+        group(&mut m, &args).unwrap();
+        assert_eq!(vec!["directed", "plain"], m.groups["rack2"]);
+
+        // The second removal fails, so the first one is undone too
+        let args = GroupArgs {
+            name: Some("rack2".to_string()),
+            remove: vec!["plain".to_string(), "plain".to_string()],
+            ..Default::default()
+        };
+        assert!(group(&mut m, &args).is_err());
+        assert_eq!(vec!["directed", "plain"], m.groups["rack2"]);
+    }
+
+    #[test]
+    fn set_is_all_or_nothing() {
+        let mut m = demo_machines();
+        let args = SetArgs {
+            name: Some("plain".to_string()),
+            add_macs: vec!["00:11:22:33:44:55".to_string()],
+            port: Some("not a port".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(set(&mut m, &args), Err(WakerError::Parse(_))));
+        assert_eq!(1, m.list[0].macs.len());
+
+        let last_mac = SetArgs {
+            name: Some("plain".to_string()),
+            remove_macs: vec!["aa:bb:cc:dd:ee:01".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(set(&mut m, &last_mac), Err(WakerError::Config(_))));
+
+        let missing = SetArgs {
+            name: Some("No_Such_Machine".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(set(&mut m, &missing), Err(WakerError::Config(_))));
+    }
+}
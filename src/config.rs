@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::error::WakerError;
+use crate::host::Host;
+use crate::machines::Machines;
+use crate::migrate;
+
+/// Serialization formats the config file can be written in, picked by file extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+/// Names accepted by "waker config convert"
+pub const FORMAT_NAMES: [&str; 3] = ["json", "toml", "yaml"];
+
+/// File names looked for in the config directory, in order of preference
+pub const CONFIG_NAMES: [&str; 4] = ["waker.json", "waker.toml", "waker.yaml", "waker.yml"];
+
+/// Config shared by every user of the machine. It is never written, its hosts and groups are
+/// merged under the ones of the per-user config.
+pub const SYSTEM_CONFIG: &str = "/etc/waker/hosts.json";
+
+/// Environment variable naming the per-user config file, --config takes precedence over it
+pub const CONFIG_ENV: &str = "WAKER_CONFIG";
+
+impl ConfigFormat {
+    pub fn from_name(name: &str) -> Option<ConfigFormat> {
+        match name {
+            "json" => Some(ConfigFormat::Json),
+            "toml" => Some(ConfigFormat::Toml),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Format of a config file, judged by its extension. Anything unknown is read as JSON.
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| ConfigFormat::from_name(&extension.to_ascii_lowercase()))
+            .unwrap_or(ConfigFormat::Json)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "json",
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Yaml => "yaml",
+        }
+    }
+
+    pub fn serialize(&self, machines: &Machines) -> Result<String, WakerError> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::to_string_pretty(machines)?),
+            ConfigFormat::Toml => toml::to_string_pretty(machines).map_err(|what| WakerError::Config(what.to_string())),
+            ConfigFormat::Yaml => serde_yaml::to_string(machines).map_err(|what| WakerError::Config(what.to_string())),
+        }
+    }
+
+    /// Parses a config, upgrading it to the current layout if it is older.
+    /// Machines::migrated_from tells whether that happened.
+    pub fn deserialize(&self, text: &str) -> Result<Machines, WakerError> {
+        let (config, version) = migrate::migrate(self.deserialize_value(text)?)?;
+        let mut machines: Machines = serde_json::from_value(config)?;
+        if version < migrate::CURRENT_VERSION {
+            machines.migrated_from = Some(version);
+        }
+        Ok(machines)
+    }
+
+    /// Parses a config without interpreting it, so older layouts can be migrated
+    pub fn deserialize_value(&self, text: &str) -> Result<Value, WakerError> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::from_str(text)?),
+            ConfigFormat::Toml => toml::from_str(text).map_err(|what| WakerError::Config(what.to_string())),
+            ConfigFormat::Yaml => serde_yaml::from_str(text).map_err(|what| WakerError::Config(what.to_string())),
+        }
+    }
+}
+
+/// Hosts and groups of the system config, see Machines::merge_system_layer
+#[derive(Clone)]
+pub struct SystemLayer {
+    pub path: PathBuf,
+    pub hosts: Vec<Host>,
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Which config file a host in the merged list comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    System, // The read-only system config
+    User,   // The per-user config, including changed copies of system hosts
+}
+
+impl std::fmt::Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layer::System => f.pad("system"),
+            Layer::User => f.pad("user"),
+        }
+    }
+}
+
+/// Reads the system config, if there is one. Older layouts are upgraded in memory only.
+pub fn load_system_layer(path: &Path) -> Result<Option<SystemLayer>, WakerError> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let text = std::fs::read_to_string(path)?;
+    let machines = ConfigFormat::from_path(path)
+        .deserialize(&text)
+        .map_err(|what| match what {
+            WakerError::Config(what) => WakerError::Config(format!("{}: {}", path.display(), what)),
+            other => other,
+        })?;
+    Ok(Some(SystemLayer {
+        path: path.to_path_buf(),
+        hosts: machines.list,
+        groups: machines.groups,
+    }))
+}
+
+/// The per-user config file named by --config or else the WAKER_CONFIG environment variable,
+/// if either is given
+pub fn config_override(flag: Option<String>) -> Option<PathBuf> {
+    flag.or_else(|| std::env::var(CONFIG_ENV).ok())
+        .filter(|path| !path.trim().is_empty())
+        .map(PathBuf::from)
+}
+
+/// Subcommands of "waker config"
+pub enum ConfigCommand {
+    Convert(ConfigFormat), // Rewrite the config file in another format, replacing the old file
+}
+
+/// The config file in the given directory. The first existing one of CONFIG_NAMES is used,
+/// waker.json if there is none yet.
+pub fn find_config(dir: &Path) -> PathBuf {
+    CONFIG_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| dir.join(CONFIG_NAMES[0]))
+}
+
+/// Replaces the contents of a file without ever leaving it empty or half written. The contents
+/// go to a temporary file in the same directory, which is synced and then renamed over the
+/// original. Permissions of an existing file are kept.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), WakerError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("waker");
+    let temp_path = dir.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let result = (|| -> Result<(), WakerError> {
+        let mut temp = OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            temp.set_permissions(metadata.permissions())?;
+        }
+        temp.write_all(contents)?;
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        // Make the rename itself durable. Directories can not be opened like this elsewhere.
+        #[cfg(unix)]
+        File::open(&dir)?.sync_all()?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Advisory lock on a config file, held for a whole read-modify-write cycle and released when
+/// dropped. The lock is taken on "<config>.lock" rather than the config itself, since writes
+/// replace the config file and a lock on the old one would protect nothing. Only unix has flock,
+/// elsewhere the lock file is created but nothing is locked.
+pub struct ConfigLock {
+    _file: File, // Closing it releases the lock
+}
+
+impl ConfigLock {
+    fn lock_path(config_path: &Path) -> PathBuf {
+        let file_name = config_path.file_name().and_then(|name| name.to_str()).unwrap_or("waker");
+        config_path.with_file_name(format!("{}.lock", file_name))
+    }
+
+    fn open(config_path: &Path) -> Result<File, WakerError> {
+        Ok(OpenOptions::new().write(true).create(true).truncate(false).open(ConfigLock::lock_path(config_path))?)
+    }
+
+    /// Takes the lock if no other waker holds it
+    pub fn try_acquire(config_path: &Path) -> Result<Option<ConfigLock>, WakerError> {
+        let file = ConfigLock::open(config_path)?;
+        match flock(&file, false)? {
+            true => Ok(Some(ConfigLock { _file: file })),
+            false => Ok(None),
+        }
+    }
+
+    /// Takes the lock, waiting for other wakers to finish first
+    pub fn acquire(config_path: &Path) -> Result<ConfigLock, WakerError> {
+        let file = ConfigLock::open(config_path)?;
+        flock(&file, true)?;
+        Ok(ConfigLock { _file: file })
+    }
+}
+
+/// Takes an exclusive lock on the file, returning false if it does not wait and another
+/// process holds the lock
+#[cfg(unix)]
+fn flock(file: &File, wait: bool) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = match wait {
+        true => libc::LOCK_EX,
+        false => libc::LOCK_EX | libc::LOCK_NB,
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.kind() {
+        std::io::ErrorKind::WouldBlock => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(unix))]
+fn flock(_file: &File, _wait: bool) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+/// Writes the hosts next to the current config file in another format and, if remove_old is
+/// set, removes the old file, so the new one is picked up from then on. The new file is read back
+/// first, and nothing is removed unless it holds exactly the same configuration. Config files
+/// named by --config or WAKER_CONFIG should be kept, as the override still points at them.
+pub fn convert(
+    config_path: &Path,
+    machines: &Machines,
+    format: ConfigFormat,
+    remove_old: bool,
+) -> Result<PathBuf, WakerError> {
+    if ConfigFormat::from_path(config_path) == format {
+        return Err(WakerError::Config(format!("{} is already {}", config_path.display(), format.extension())));
+    }
+    let new_path = config_path.with_extension(format.extension());
+    if new_path.exists() {
+        return Err(WakerError::Config(format!("{} already exists", new_path.display())));
+    }
+
+    // Hosts of the system config stay where they are
+    let machines = machines.user_layer();
+    let serialized = format.serialize(&machines)?;
+    if serde_json::to_value(format.deserialize(&serialized)?)? != serde_json::to_value(&machines)? {
+        return Err(WakerError::Config(format!("Converting to {} would lose information", format.extension())));
+    }
+    write_atomic(&new_path, serialized.as_bytes())?;
+    if remove_old {
+        std::fs::remove_file(config_path)?;
+    }
+    Ok(new_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::demo_machines;
+
+    #[test]
+    fn formats_round_trip() {
+        let mut m = demo_machines();
+        m.list[1].repeat = Some(3);
+        m.list[1].burst = Some(true);
+        m.pacing.interval = Some(250);
+        for format in [ConfigFormat::Json, ConfigFormat::Toml, ConfigFormat::Yaml] {
+            let parsed = format.deserialize(&format.serialize(&m).unwrap()).unwrap();
+            assert_eq!(serde_json::to_value(&m).unwrap(), serde_json::to_value(&parsed).unwrap());
+        }
+        let empty = Machines::new();
+        let parsed = ConfigFormat::Toml.deserialize(&ConfigFormat::Toml.serialize(&empty).unwrap()).unwrap();
+        assert!(parsed.list.is_empty());
+    }
+
+    #[test]
+    fn format_by_extension() {
+        assert_eq!(ConfigFormat::Toml, ConfigFormat::from_path(Path::new("/etc/waker.toml")));
+        assert_eq!(ConfigFormat::Yaml, ConfigFormat::from_path(Path::new("waker.YML")));
+        assert_eq!(ConfigFormat::Json, ConfigFormat::from_path(Path::new("waker")));
+        assert!(matches!(ConfigFormat::Toml.deserialize("list = 3"), Err(WakerError::Config(_))));
+    }
+
+    #[test]
+    fn atomic_writes_and_locks() {
+        let dir = std::env::temp_dir().join(format!("waker-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("waker.json");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+
+        let lock = ConfigLock::try_acquire(&path).unwrap();
+        let contended = ConfigLock::try_acquire(&path).unwrap();
+        drop(lock);
+        let released = ConfigLock::try_acquire(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!("second", contents);
+        assert_eq!(1, files); // No temporary files left behind
+        assert_eq!(cfg!(unix), contended.is_none()); // Nothing is locked without flock
+        assert!(released.is_some());
+    }
+
+    #[test]
+    fn convert_replaces_file() {
+        let dir = std::env::temp_dir().join(format!("waker-convert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json_path = find_config(&dir);
+        assert_eq!(dir.join("waker.json"), json_path);
+
+        std::fs::write(&json_path, ConfigFormat::Json.serialize(&demo_machines()).unwrap()).unwrap();
+        let machines = Machines::from_file(&json_path).unwrap();
+        let toml_path = convert(&json_path, &machines, ConfigFormat::Toml, true).unwrap();
+        let found = find_config(&dir);
+        let json_removed = !json_path.exists();
+        let reloaded = Machines::from_file(&found);
+        let same_format = convert(&toml_path, &machines, ConfigFormat::Toml, true);
+        let yaml_path = convert(&toml_path, &machines, ConfigFormat::Yaml, false).unwrap();
+        let toml_kept = toml_path.exists() && yaml_path.exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dir.join("waker.toml"), found);
+        assert!(json_removed);
+        assert_eq!(4, reloaded.unwrap().list.len());
+        assert!(matches!(same_format, Err(WakerError::Config(_))));
+        assert!(toml_kept);
+    }
+}
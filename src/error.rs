@@ -0,0 +1,87 @@
+use std::fmt;
+
+/// Every error that can occur while parsing addresses, handling the config file or waking hosts
+#[derive(Debug)]
+pub enum WakerError {
+    Parse(String),             // Malformed MAC address, IP address or password
+    Io(std::io::Error),        // Reading or writing files
+    Config(String),            // Config file could not be (de)serialized
+    Network(std::io::Error),   // Sending a magic packet failed
+    Timeout(String),           // A woken host did not come up in time
+}
+
+impl WakerError {
+    /// Process exit code for this class of error, following the BSD sysexits convention
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            WakerError::Parse(_) => 65,   // EX_DATAERR
+            WakerError::Network(_) => 69, // EX_UNAVAILABLE
+            WakerError::Timeout(_) => 75, // EX_TEMPFAIL
+            WakerError::Io(_) => 74,      // EX_IOERR
+            WakerError::Config(_) => 78,  // EX_CONFIG
+        }
+    }
+}
+
+impl fmt::Display for WakerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WakerError::Parse(what) => write!(f, "{}", what),
+            WakerError::Io(what) => write!(f, "I/O error: {}", what),
+            WakerError::Config(what) => write!(f, "Config error: {}", what),
+            WakerError::Network(what) => write!(f, "Network error: {}", what),
+            WakerError::Timeout(what) => write!(f, "Timed out: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for WakerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WakerError::Io(what) | WakerError::Network(what) => Some(what),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for WakerError {
+    fn from(what: std::io::Error) -> Self {
+        WakerError::Io(what)
+    }
+}
+
+impl From<serde_json::Error> for WakerError {
+    fn from(what: serde_json::Error) -> Self {
+        WakerError::Config(what.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distinct_exit_codes() {
+        let errors = [
+            WakerError::Parse("bad".to_string()),
+            WakerError::Io(std::io::Error::other("io")),
+            WakerError::Config("bad".to_string()),
+            WakerError::Network(std::io::Error::other("net")),
+            WakerError::Timeout("slow".to_string()),
+        ];
+        for (i, a) in errors.iter().enumerate() {
+            assert_ne!(0, a.exit_code());
+            for b in &errors[i + 1..] {
+                assert_ne!(a.exit_code(), b.exit_code());
+            }
+        }
+    }
+
+    #[test]
+    fn from_conversions() {
+        let io: WakerError = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+        assert!(matches!(io, WakerError::Io(_)));
+        let json: WakerError = serde_json::from_str::<u8>("nope").unwrap_err().into();
+        assert!(matches!(json, WakerError::Config(_)));
+    }
+}
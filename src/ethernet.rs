@@ -0,0 +1,135 @@
+use std::io;
+
+// Magic packets can be sent directly in an Ethernet frame instead of in a UDP datagram.
+// This skips the IP layer entirely, so it works on segments where broadcast UDP is filtered, or
+// when the sending host has no IP address configured. The frame is laid out as:
+// | destination MAC (6) | source MAC (6) | EtherType 0x0842 (2) | magic packet payload |
+
+/// EtherType reserved for Wake-on-LAN frames
+pub const ETHERTYPE_WOL: u16 = 0x0842;
+
+/// Destination used for wake frames, reaching every NIC on the segment
+pub const BROADCAST_MAC: [u8; 6] = [0xFF; 6];
+
+// Frames shorter than this (excluding the FCS) are padded by the sender.
+const MIN_FRAME_LEN: usize = 60;
+
+/// Builds a raw Ethernet frame carrying the given payload with EtherType 0x0842
+pub fn build_frame(dst_mac: &[u8; 6], src_mac: &[u8; 6], payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(MIN_FRAME_LEN.max(14 + payload.len()));
+    frame.extend_from_slice(dst_mac);
+    frame.extend_from_slice(src_mac);
+    frame.extend_from_slice(&ETHERTYPE_WOL.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < MIN_FRAME_LEN {
+        frame.resize(MIN_FRAME_LEN, 0);
+    }
+    frame
+}
+
+/// Reads the hardware address of a network interface from sysfs
+pub fn interface_mac(interface: &str) -> io::Result<[u8; 6]> {
+    let path = format!("/sys/class/net/{}/address", interface);
+    let mac_str = std::fs::read_to_string(path)?;
+    let bytes: Vec<u8> = mac_str
+        .trim()
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid interface address"))?;
+    if bytes.len() != 6 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid interface address"));
+    }
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&bytes);
+    Ok(mac)
+}
+
+/// Sends a complete Ethernet frame out of the named interface through an AF_PACKET socket.
+/// This usually requires root or CAP_NET_RAW.
+#[cfg(target_os = "linux")]
+pub fn send_frame(interface: &str, frame: &[u8]) -> io::Result<()> {
+    let if_name = std::ffi::CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid interface name"))?;
+    let protocol = ETHERTYPE_WOL.to_be();
+
+    unsafe {
+        let if_index = libc::if_nametoindex(if_name.as_ptr());
+        if if_index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as libc::c_int);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut addr: libc::sockaddr_ll = std::mem::zeroed();
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = if_index as libc::c_int;
+        addr.sll_halen = 6;
+        addr.sll_addr[..6].copy_from_slice(&frame[..6]);
+
+        let sent = libc::sendto(
+            fd,
+            frame.as_ptr() as *const libc::c_void,
+            frame.len(),
+            0,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        );
+        let result = if sent < 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+        libc::close(fd);
+        result
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_frame(_interface: &str, _frame: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Raw Ethernet is only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::MagicPacket;
+
+    #[test]
+    fn frame_layout() {
+        let src = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        let packet = MagicPacket::new(&[0x10; 6]);
+        let frame = build_frame(&BROADCAST_MAC, &src, &packet.bytes);
+
+        assert_eq!(14 + 102, frame.len());
+        assert_eq!(BROADCAST_MAC, frame[0..6]);
+        assert_eq!(src, frame[6..12]);
+        assert_eq!([0x08, 0x42], frame[12..14]);
+        assert_eq!(packet.bytes[..], frame[14..]);
+    }
+
+    #[test]
+    fn frame_secure_payload() {
+        let packet = MagicPacket::new_secure(&[0x10; 6], &[1, 2, 3, 4]).unwrap();
+        let frame = build_frame(&BROADCAST_MAC, &[0x00; 6], &packet.bytes);
+        assert_eq!(14 + 106, frame.len());
+        assert_eq!([1, 2, 3, 4], frame[frame.len() - 4..]);
+    }
+
+    #[test]
+    fn frame_padding() {
+        let frame = build_frame(&BROADCAST_MAC, &[0x00; 6], &[0xAA; 4]);
+        assert_eq!(60, frame.len());
+        assert_eq!([0xAA; 4], frame[14..18]);
+        assert!(frame[18..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn loopback_mac() {
+        // The loopback interface exists on every linux box, with an all-zero address
+        if std::path::Path::new("/sys/class/net/lo/address").exists() {
+            assert_eq!([0x00; 6], interface_mac("lo").unwrap());
+        }
+        assert!(interface_mac("no-such-interface0").is_err());
+    }
+}
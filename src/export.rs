@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::host::Host;
+use crate::machines::Machines;
+
+/// Formats the configuration can be exported to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,         // One row per host, multiple values separated by semicolons
+    Ethers,      // /etc/ethers, one line per MAC
+    AnsibleIni,  // Ansible INI inventory, tags and groups become inventory groups
+    AnsibleYaml, // Ansible YAML inventory, same layout as the INI one
+    Hosts,       // /etc/hosts, one line per IP
+}
+
+/// Names accepted by "waker export --format"
+pub const FORMAT_NAMES: [&str; 5] = ["csv", "ethers", "ansible-ini", "ansible-yaml", "hosts"];
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "ethers" => Some(Format::Ethers),
+            "ansible-ini" => Some(Format::AnsibleIni),
+            "ansible-yaml" => Some(Format::AnsibleYaml),
+            "hosts" => Some(Format::Hosts),
+            _ => None,
+        }
+    }
+}
+
+/// Arguments to "waker export"
+pub struct ExportArgs {
+    pub format: Format,
+    pub output: Option<PathBuf>, // Written to stdout if not given
+}
+
+/// Renders every configured host in the given format
+pub fn render(machines: &Machines, format: Format) -> String {
+    match format {
+        Format::Csv => csv(machines),
+        Format::Ethers => ethers(machines),
+        Format::AnsibleIni => ansible_ini(machines),
+        Format::AnsibleYaml => ansible_yaml(machines),
+        Format::Hosts => hosts(machines),
+    }
+}
+
+fn joined<T: ToString>(values: &[T], separator: &str) -> String {
+    values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(separator)
+}
+
+/// Quotes a CSV field if it has to be, doubling any quotes inside it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field.to_string()
+}
+
+fn csv(machines: &Machines) -> String {
+    let mut out = String::from("name,macs,ips,tags,groups\n");
+    for host in &machines.list {
+        let fields = [
+            host.name.clone(),
+            joined(&host.macs, ";"),
+            joined(&host.ips, ";"),
+            host.tags.join(";"),
+            machines.groups_of(&host.name).join(";"),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Host names can hold anything, but these formats take host names as DNS knows them: letters,
+/// digits and hyphens. Runs of anything else become a single hyphen, and a name with nothing
+/// left of it falls back to the host's first MAC address.
+fn host_name(host: &Host) -> String {
+    let mut name = String::new();
+    for c in host.name.chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    let name = name.trim_end_matches('-');
+    if name.is_empty() {
+        return host.macs.first().map(|mac| mac.to_string().replace(':', "-")).unwrap_or_default();
+    }
+    name.to_string()
+}
+
+fn ethers(machines: &Machines) -> String {
+    let mut out = String::from("# Generated by waker\n");
+    for host in &machines.list {
+        for mac in &host.macs {
+            out.push_str(&format!("{} {}\n", mac, host_name(host)));
+        }
+    }
+    out
+}
+
+fn hosts(machines: &Machines) -> String {
+    let mut out = String::from("# Generated by waker\n");
+    for host in &machines.list {
+        if host.ips.is_empty() {
+            out.push_str(&format!("# {} has no IP address\n", host_name(host)));
+        }
+        for ip in &host.ips {
+            out.push_str(&format!("{}\t{}\n", ip, host_name(host)));
+        }
+    }
+    out
+}
+
+/// Inventory group holding every host
+const HOSTS_GROUP: &str = "waker";
+
+/// Ansible group names may only hold letters, digits and underscores. A tag or group named
+/// like the group of all hosts gets a suffix, so the two stay apart.
+fn ansible_group(name: &str) -> String {
+    let group: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    match group == HOSTS_GROUP {
+        true => format!("{}_group", group),
+        false => group,
+    }
+}
+
+/// Tags and named groups both become inventory groups, merged if their names collide
+fn ansible_groups(machines: &Machines) -> BTreeMap<String, Vec<String>> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for host in &machines.list {
+        let memberships = host.tags.iter().map(|tag| tag.as_str()).chain(machines.groups_of(&host.name));
+        for group in memberships {
+            let members = groups.entry(ansible_group(group)).or_default();
+            let name = host_name(host);
+            if !members.contains(&name) {
+                members.push(name);
+            }
+        }
+    }
+    groups
+}
+
+/// Host variables, ansible_host is the first IP address and wol_macs lists every MAC
+fn ansible_vars(host: &Host) -> Vec<(&'static str, String)> {
+    let mut vars = Vec::new();
+    if let Some(ip) = host.ips.first() {
+        vars.push(("ansible_host", ip.to_string()));
+    }
+    vars.push(("wol_macs", joined(&host.macs, ",")));
+    vars
+}
+
+fn ansible_ini(machines: &Machines) -> String {
+    let mut out = format!("[{}]\n", HOSTS_GROUP);
+    for host in &machines.list {
+        let vars: Vec<String> = ansible_vars(host).iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        out.push_str(&format!("{} {}\n", host_name(host), vars.join(" ")));
+    }
+    for (group, members) in ansible_groups(machines) {
+        out.push_str(&format!("\n[{}]\n", group));
+        for member in members {
+            out.push_str(&member);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Double quoted YAML scalar
+fn yaml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn ansible_yaml(machines: &Machines) -> String {
+    let mut out = format!("all:\n  children:\n    {}:\n      hosts:\n", HOSTS_GROUP);
+    for host in &machines.list {
+        out.push_str(&format!("        {}:\n", yaml_string(&host_name(host))));
+        for (key, value) in ansible_vars(host) {
+            out.push_str(&format!("          {}: {}\n", key, yaml_string(&value)));
+        }
+    }
+    for (group, members) in ansible_groups(machines) {
+        out.push_str(&format!("    {}:\n      hosts:\n", group));
+        for member in members {
+            out.push_str(&format!("        {}: {{}}\n", yaml_string(&member)));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::demo_machines;
+
+    #[test]
+    fn csv_export() {
+        let mut m = demo_machines();
+        m.list[0].name = "Office PC, 2nd".to_string();
+        let expected = "name,macs,ips,tags,groups\n\
+                        \"Office PC, 2nd\",AA:BB:CC:DD:EE:01,,lab,\n\
+                        directed,AA:BB:CC:DD:EE:02,10.1.2.3,build-farm,rack2\n\
+                        bound,AA:BB:CC:DD:EE:03,192.168.9.10;fd00::9,,\n\
+                        layer2,AA:BB:CC:DD:EE:04;AA:BB:CC:DD:EE:05,,,\n";
+        assert_eq!(expected, render(&m, Format::Csv));
+    }
+
+    #[test]
+    fn ethers_and_hosts_export() {
+        let expected = "# Generated by waker\n\
+                        AA:BB:CC:DD:EE:01 plain\n\
+                        AA:BB:CC:DD:EE:02 directed\n\
+                        AA:BB:CC:DD:EE:03 bound\n\
+                        AA:BB:CC:DD:EE:04 layer2\n\
+                        AA:BB:CC:DD:EE:05 layer2\n";
+        assert_eq!(expected, render(&demo_machines(), Format::Ethers));
+        let expected = "# Generated by waker\n\
+                        # plain has no IP address\n\
+                        10.1.2.3\tdirected\n\
+                        192.168.9.10\tbound\n\
+                        fd00::9\tbound\n\
+                        # layer2 has no IP address\n";
+        assert_eq!(expected, render(&demo_machines(), Format::Hosts));
+    }
+
+    #[test]
+    fn ansible_export() {
+        let expected = "[waker]\n\
+                        plain wol_macs=AA:BB:CC:DD:EE:01\n\
+                        directed ansible_host=10.1.2.3 wol_macs=AA:BB:CC:DD:EE:02\n\
+                        bound ansible_host=192.168.9.10 wol_macs=AA:BB:CC:DD:EE:03\n\
+                        layer2 wol_macs=AA:BB:CC:DD:EE:04,AA:BB:CC:DD:EE:05\n\
+                        \n[build_farm]\ndirected\n\
+                        \n[lab]\nplain\n\
+                        \n[rack2]\ndirected\n";
+        assert_eq!(expected, render(&demo_machines(), Format::AnsibleIni));
+
+        let yaml = render(&demo_machines(), Format::AnsibleYaml);
+        assert!(yaml.starts_with("all:\n  children:\n    waker:\n      hosts:\n        \"plain\":"));
+        assert!(yaml.contains("          ansible_host: \"10.1.2.3\"\n"));
+        assert!(yaml.contains("    build_farm:\n      hosts:\n        \"directed\": {}\n"));
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let mut m = demo_machines();
+        m.list[0].name = "Office PC, 2nd".to_string();
+        m.list[1].name = "--".to_string();
+        m.list[1].tags.push("waker".to_string());
+        m.list[2].name = "Bound (eth1)".to_string();
+        let expected = "# Generated by waker\n\
+                        # Office-PC-2nd has no IP address\n\
+                        10.1.2.3\tAA-BB-CC-DD-EE-02\n\
+                        192.168.9.10\tBound-eth1\n\
+                        fd00::9\tBound-eth1\n\
+                        # layer2 has no IP address\n";
+        assert_eq!(expected, render(&m, Format::Hosts));
+
+        // A tag named like the group of all hosts does not add to it
+        let ini = render(&m, Format::AnsibleIni);
+        assert_eq!(1, ini.matches("[waker]\n").count());
+        assert!(ini.contains("\n[waker_group]\nAA-BB-CC-DD-EE-02\n"));
+        let yaml = render(&m, Format::AnsibleYaml);
+        assert!(yaml.contains("    waker_group:\n      hosts:\n        \"AA-BB-CC-DD-EE-02\": {}\n"));
+    }
+}
@@ -0,0 +1,9 @@
+// Test data shared by the unit tests
+
+use crate::machines::Machines;
+
+/// Four hosts covering the ways of reaching one: plain broadcast, directed broadcast from a
+/// source address, a broadcast address on a bound interface, and raw Ethernet with a password
+pub fn demo_machines() -> Machines {
+    serde_json::from_str(include_str!("../tests/fixtures/machines.json")).unwrap()
+}
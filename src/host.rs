@@ -1,34 +1,391 @@
-use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use crate::packet::MagicPacket;
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::error::WakerError;
+use crate::mac::MacAddress;
+use crate::pacing::{Delivery, Pacing, WakePlan};
+use crate::packet::{self, MagicPacket};
+use crate::transport::{Route, SystemTransport, Transport};
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
 pub struct Host {
     pub name: String,
-    pub macs: Vec<String>,
-    pub ips: Vec<String>,
+    pub macs: Vec<MacAddress>,
+    pub ips: Vec<IpAddr>,
+    /// SecureOn password, in the format accepted by MagicPacket::parse_password
+    #[serde(default, deserialize_with = "deserialize_password", skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// If set, magic packets are sent as raw Ethernet frames on this interface instead of UDP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ethernet: Option<String>,
+    /// Broadcast address to send to, instead of 255.255.255.255
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broadcast: Option<Ipv4Addr>,
+    /// IPv6 address to send to instead of an IPv4 broadcast, usually packet::ALL_NODES (ff02::1).
+    /// Link-local addresses are scoped to the interface.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+    /// Subnet prefix length of the host. Together with an IPv4 address in ips, this gives a
+    /// directed broadcast address when no explicit broadcast address is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<u8>,
+    /// UDP port to send to, defaults to 9
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Network device the UDP socket is bound to. IPv6 packets are not bound to it, it is the
+    /// scope of link-local IPv6 addresses instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Local address the UDP socket is bound to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Ipv4Addr>,
+    /// Times every magic packet is sent, see Pacing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<u32>,
+    /// Milliseconds between repeats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    /// Send to every broadcast and unicast address of the host at once, see routes()
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<bool>,
+    /// Free form labels, hosts sharing a tag can be woken together
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 
 impl Host {
-    pub fn new<S: Into<String>>(name: S, mac: S, ipv4: S) -> Host {
+    pub fn new<S: Into<String>>(name: S, mac: MacAddress, ip: IpAddr) -> Host {
         Host {
             name: name.into(),
-            macs: vec![mac.into()],
-            ips: vec![ipv4.into()],
+            macs: vec![mac],
+            ips: vec![ip],
+            ..Default::default()
+        }
+    }
+
+    /// Where UDP magic packets for this host are sent.
+    /// An explicit broadcast address wins, followed by the directed broadcast of the first IPv4
+    /// address when a prefix length is known, falling back to the limited broadcast address.
+    pub fn destination(&self) -> SocketAddrV4 {
+        let port = self.port.unwrap_or(packet::DEFAULT_PORT);
+        if let Some(broadcast) = self.broadcast {
+            return SocketAddrV4::new(broadcast, port);
+        }
+        if let Some(prefix) = self.prefix {
+            let ipv4 = self.ips.iter().find_map(|ip| match ip {
+                IpAddr::V4(ipv4) => Some(*ipv4),
+                IpAddr::V6(_) => None,
+            });
+            if let Some(ipv4) = ipv4 {
+                return SocketAddrV4::new(packet::directed_broadcast(ipv4, prefix), port);
+            }
+        }
+        SocketAddrV4::new(Ipv4Addr::BROADCAST, port)
+    }
+
+    /// Local address UDP magic packets for this host are sent from
+    pub fn source_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.source.unwrap_or(Ipv4Addr::UNSPECIFIED), 0)
+    }
+
+    /// Where magic packets for this host are sent over IPv6, if they are. Link-local addresses
+    /// carry the index of the host's interface as scope id, which is 0 if that is unknown.
+    pub fn destination6(&self) -> Option<SocketAddrV6> {
+        let ipv6 = self.ipv6?;
+        Some(SocketAddrV6::new(ipv6, self.port.unwrap_or(packet::DEFAULT_PORT), 0, self.scope(&ipv6).unwrap_or(0)))
+    }
+
+    /// Addresses to probe this host's IPs at, with link-local IPv6 ones scoped to its interface.
+    /// The port is left for the prober to fill in.
+    pub fn probe_addrs(&self) -> Vec<SocketAddr> {
+        self.ips
+            .iter()
+            .map(|ip| match ip {
+                IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(*ip, 0)),
+                IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(*ip, 0, 0, self.scope(ip).unwrap_or(0))),
+            })
+            .collect()
+    }
+
+    /// Scope id for sending to an IPv6 address, 0 for addresses that need none. None if the
+    /// address is link-local but the host has no interface, or the interface does not exist.
+    fn scope(&self, ip: &Ipv6Addr) -> Option<u32> {
+        if !packet::is_link_local(ip) {
+            return Some(0);
+        }
+        self.interface.as_deref().and_then(packet::interface_index)
+    }
+
+    /// Builds one magic packet per configured MAC, with the SecureOn password appended if set
+    pub fn packets(&self) -> Result<Vec<MagicPacket>, WakerError> {
+        let password = match &self.password {
+            Some(password) => Some(MagicPacket::parse_password(password)?),
+            None => None,
+        };
+        let mut packets = Vec::new();
+        for mac in &self.macs {
+            match &password {
+                Some(password) => packets.push(MagicPacket::new_secure(mac.bytes(), password)?),
+                None => packets.push(MagicPacket::new(mac.bytes())),
+            }
+        }
+        Ok(packets)
+    }
+
+    /// How packets for this host are sent: as raw Ethernet frames on the given interface if one
+    /// is given, falling back to the host's own Ethernet interface, then to UDP over IPv6 if an
+    /// IPv6 destination is set, then to UDP over IPv4.
+    pub fn route(&self, ethernet: Option<&str>) -> Route {
+        if let Some(interface) = ethernet.or(self.ethernet.as_deref()) {
+            return Route::Ethernet { interface: interface.to_string() };
+        }
+        match self.destination6() {
+            Some(to) => Route::Udp { to: to.into(), from: unspecified(&to.into()), device: None },
+            None => Route::Udp {
+                to: self.destination().into(),
+                from: self.source_addr().into(),
+                device: self.interface.clone(),
+            },
         }
     }
 
-    pub fn wake(&self) {
-        for mac_str in &self.macs {
-            MagicPacket::from_str(&mac_str).unwrap().send().unwrap();
+    /// Every route a wake of this host sends on. That is route() alone, unless bursting over
+    /// UDP, which adds the explicit, directed and limited IPv4 broadcast addresses as well as
+    /// every address of the host itself. Fails if a link-local IPv6 destination can not be
+    /// scoped to an interface, extra link-local addresses are left out instead.
+    pub fn routes(&self, ethernet: Option<&str>, burst: bool) -> Result<Vec<Route>, WakerError> {
+        let route = self.route(ethernet);
+        if let (Route::Udp { .. }, Some(ipv6)) = (&route, self.ipv6) {
+            if self.scope(&ipv6).is_none() {
+                return Err(match &self.interface {
+                    Some(interface) => WakerError::Network(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No network interface named \"{}\" to send to {} on", interface, ipv6),
+                    )),
+                    None => WakerError::Config(format!("{} needs an interface to send to {}, which is link-local", self.name, ipv6)),
+                });
+            }
+        }
+        if !burst || !matches!(route, Route::Udp { .. }) {
+            return Ok(vec![route]);
+        }
+
+        let port = self.port.unwrap_or(packet::DEFAULT_PORT);
+        let mut destinations: Vec<SocketAddr> = Vec::new();
+        let mut ipv4 = |address: Ipv4Addr| destinations.push(SocketAddr::V4(SocketAddrV4::new(address, port)));
+        if let Some(broadcast) = self.broadcast {
+            ipv4(broadcast);
+        }
+        for ip in &self.ips {
+            if let (IpAddr::V4(ip), Some(prefix)) = (ip, self.prefix) {
+                ipv4(packet::directed_broadcast(*ip, prefix));
+            }
+        }
+        ipv4(Ipv4Addr::BROADCAST);
+        for ip in &self.ips {
+            match ip {
+                IpAddr::V4(ip) => destinations.push(SocketAddr::V4(SocketAddrV4::new(*ip, port))),
+                IpAddr::V6(ip) => {
+                    if let Some(scope) = self.scope(ip) {
+                        destinations.push(SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, scope)));
+                    }
+                }
+            }
+        }
+
+        let mut routes = vec![route];
+        for to in destinations {
+            let extra = match to {
+                SocketAddr::V4(_) => Route::Udp { to, from: self.source_addr().into(), device: self.interface.clone() },
+                SocketAddr::V6(_) => Route::Udp { to, from: unspecified(&to), device: None },
+            };
+            if !routes.contains(&extra) {
+                routes.push(extra);
+            }
         }
+        Ok(routes)
+    }
+
+    /// The pacing this host asks for, anything it leaves unset is up to the config
+    pub fn pacing(&self) -> Pacing {
+        Pacing { repeat: self.repeat, interval: self.interval, burst: self.burst }
+    }
+
+    /// Wake host through the operating system's sockets, see route() for how
+    pub fn wake(&self, ethernet: Option<&str>) -> Result<(), WakerError> {
+        self.wake_with(&SystemTransport, ethernet)
+    }
+
+    /// Wake host, handing every magic packet to the given transport
+    pub fn wake_with(&self, transport: &dyn Transport, ethernet: Option<&str>) -> Result<(), WakerError> {
+        self.wake_paced(transport, ethernet, &self.pacing())
+    }
+
+    /// The packets of a wake with the given pacing and where they go, see WakePlan
+    pub fn wake_plan(&self, ethernet: Option<&str>, pacing: &Pacing) -> Result<WakePlan, WakerError> {
+        Ok(WakePlan::new(self.packets()?, self.routes(ethernet, pacing.burst())?, *pacing))
+    }
+
+    /// Wake host with the given pacing rather than its own. Every round sends each packet on
+    /// every route back to back, and rounds are a pause of the transport apart. A failed send
+    /// does not stop the rest, the wake only fails if nothing of the first round got out.
+    pub fn wake_paced(&self, transport: &dyn Transport, ethernet: Option<&str>, pacing: &Pacing) -> Result<(), WakerError> {
+        let plan = self.wake_plan(ethernet, pacing)?;
+        let mut delivery = Delivery::default();
+        for round in 0..plan.rounds() {
+            if let Some(pause) = plan.pause_before(round) {
+                transport.pause(pause);
+            }
+            for (packet, route) in plan.sends() {
+                delivery.record(transport.send(packet, route));
+            }
+            delivery.end_round(round)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rejects passwords MagicPacket::parse_password can not read, so a bad config fails to load
+/// instead of failing every wake of the host
+fn deserialize_password<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let password = Option::<String>::deserialize(deserializer)?;
+    if let Some(password) = &password {
+        MagicPacket::parse_password(password).map_err(serde::de::Error::custom)?;
+    }
+    Ok(password)
+}
+
+/// The any address of the family of `to`, for binding the socket packets to it are sent from
+fn unspecified(to: &SocketAddr) -> SocketAddr {
+    match to {
+        SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
     }
 }
 
 impl std::fmt::Display for Host {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let macs_str = format!("{:?}", &self.macs);
-        let ips_str = format!("{:?}", &self.ips);
-        write!(f, "{:<16} {} - {}", self.name, macs_str, ips_str)
+        let macs: Vec<String> = self.macs.iter().map(|mac| mac.to_string()).collect();
+        let ips: Vec<String> = self.ips.iter().map(|ip| ip.to_string()).collect();
+        let macs_str = format!("{:?}", macs);
+        let ips_str = format!("{:?}", ips);
+        write!(f, "{:<16} {} - {}", self.name, macs_str, ips_str)?;
+        if !self.tags.is_empty() {
+            write!(f, " [{}]", self.tags.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_packets_with_password() {
+        let mut host = Host::new("Demo_Machine", "01:02:03:04:05:06".parse().unwrap(), "10.0.0.1".parse().unwrap());
+        assert_eq!(102, host.packets().unwrap()[0].bytes.len());
+
+        host.password = Some("aa:bb:cc:dd:ee:ff".to_string());
+        let packets = host.packets().unwrap();
+        assert_eq!(108, packets[0].bytes.len());
+        assert_eq!([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF], packets[0].bytes[102..]);
+    }
+
+    #[test]
+    fn password_roundtrip() {
+        let legacy = r#"{"name":"a","macs":["01:02:03:04:05:06"],"ips":[]}"#;
+        let host: Host = serde_json::from_str(legacy).unwrap();
+        assert!(host.password.is_none());
+        assert!(!serde_json::to_string(&host).unwrap().contains("password"));
+
+        let secure = r#"{"name":"a","macs":["01:02:03:04:05:06"],"ips":[],"password":"192.168.1.1"}"#;
+        let host: Host = serde_json::from_str(secure).unwrap();
+        assert_eq!(Some("192.168.1.1".to_string()), host.password);
+        assert_eq!(106, host.packets().unwrap()[0].bytes.len());
+
+        let malformed = r#"{"name":"a","macs":["01:02:03:04:05:06"],"ips":[],"password":"aa:bb:cc"}"#;
+        assert!(serde_json::from_str::<Host>(malformed).is_err());
+    }
+
+    #[test]
+    fn host_destination() {
+        let mut host = Host::new("Demo_Machine", "01:02:03:04:05:06".parse().unwrap(), "192.168.1.10".parse().unwrap());
+        assert_eq!("255.255.255.255:9".parse::<SocketAddrV4>().unwrap(), host.destination());
+
+        host.port = Some(7);
+        assert_eq!("255.255.255.255:7".parse::<SocketAddrV4>().unwrap(), host.destination());
+
+        host.prefix = Some(24);
+        assert_eq!("192.168.1.255:7".parse::<SocketAddrV4>().unwrap(), host.destination());
+
+        host.broadcast = Some(Ipv4Addr::new(10, 0, 0, 255));
+        assert_eq!("10.0.0.255:7".parse::<SocketAddrV4>().unwrap(), host.destination());
+
+        // A prefix without any IPv4 address to apply it to falls back to the limited broadcast
+        let mut bare = Host::new("Bare_Machine", "01:02:03:04:05:06".parse().unwrap(), "fe80::1".parse().unwrap());
+        bare.prefix = Some(24);
+        assert_eq!("255.255.255.255:9".parse::<SocketAddrV4>().unwrap(), bare.destination());
+
+        // IPv6 destinations win over all of the above, link-local ones scoped to the interface
+        assert_eq!(None, host.destination6());
+        host.ipv6 = Some(packet::ALL_NODES);
+        assert_eq!(0, host.destination6().unwrap().scope_id());
+        host.interface = Some("lo".to_string());
+        assert_eq!(packet::interface_index("lo"), Some(host.destination6().unwrap().scope_id()));
+        assert!(matches!(host.route(None), Route::Udp { to: SocketAddr::V6(_), device: None, .. }));
+        host.ipv6 = Some("2001:db8::1".parse().unwrap());
+        assert_eq!("[2001:db8::1]:7", host.destination6().unwrap().to_string());
+    }
+
+    #[test]
+    fn probe_addrs_are_scoped() {
+        let mut host = Host::new("Demo_Machine", "01:02:03:04:05:06".parse().unwrap(), "fe80::1".parse().unwrap());
+        host.ips.push("192.168.1.10".parse().unwrap());
+        host.ips.push("2001:db8::1".parse().unwrap());
+        let scopes = |host: &Host| -> Vec<u32> {
+            host.probe_addrs()
+                .iter()
+                .map(|addr| match addr {
+                    SocketAddr::V6(addr) => addr.scope_id(),
+                    SocketAddr::V4(_) => 0,
+                })
+                .collect()
+        };
+        assert_eq!(vec![0, 0, 0], scopes(&host));
+        host.interface = Some("lo".to_string());
+        assert_eq!(vec![packet::interface_index("lo").unwrap(), 0, 0], scopes(&host));
+    }
+
+    #[test]
+    fn network_fields_roundtrip() {
+        let legacy = r#"{"name":"a","macs":["01:02:03:04:05:06"],"ips":["10.1.2.3"]}"#;
+        let host: Host = serde_json::from_str(legacy).unwrap();
+        assert!(host.broadcast.is_none() && host.port.is_none() && host.interface.is_none());
+        assert_eq!("0.0.0.0:0".parse::<SocketAddrV4>().unwrap(), host.source_addr());
+
+        let json = r#"{"name":"a","macs":["01:02:03:04:05:06"],"ips":["10.1.2.3"],"prefix":8,"port":7,"interface":"eth1","source":"10.1.2.1"}"#;
+        let host: Host = serde_json::from_str(json).unwrap();
+        assert_eq!("10.255.255.255:7".parse::<SocketAddrV4>().unwrap(), host.destination());
+        assert_eq!("10.1.2.1:0".parse::<SocketAddrV4>().unwrap(), host.source_addr());
+        assert_eq!(Some("eth1".to_string()), host.interface);
+
+        let host2: Host = serde_json::from_str(&serde_json::to_string(&host).unwrap()).unwrap();
+        assert_eq!(host.destination(), host2.destination());
+    }
+
+    #[test]
+    fn invalid_addresses_rejected_on_load() {
+        let bad_mac = r#"{"name":"a","macs":["01:02:03:04:05"],"ips":[]}"#;
+        assert!(serde_json::from_str::<Host>(bad_mac).is_err());
+        let bad_ip = r#"{"name":"a","macs":["01:02:03:04:05:06"],"ips":["10.0.0.256"]}"#;
+        assert!(serde_json::from_str::<Host>(bad_ip).is_err());
+
+        let good = r#"{"name":"a","macs":["01-02-03-04-05-06"],"ips":["10.0.0.1","fe80::1"]}"#;
+        let host: Host = serde_json::from_str(good).unwrap();
+        assert_eq!("01:02:03:04:05:06", host.macs[0].to_string());
+        assert!(serde_json::to_string(&host).unwrap().contains(r#""macs":["01:02:03:04:05:06"]"#));
     }
 }
@@ -0,0 +1,533 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::error::WakerError;
+use crate::host::Host;
+use crate::mac::MacAddress;
+use crate::machines::Machines;
+use crate::sanitizers::{self, Mode};
+
+/// File formats hosts can be imported from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Waker,   // Another waker config file
+    Ethers,  // /etc/ethers, "MAC hostname-or-IP" per line
+    Dnsmasq, // dnsmasq.leases, "expiry MAC IP hostname client-id" per line
+    Dhcpd,   // ISC dhcpd.leases, "lease IP { hardware ethernet MAC; ... }" blocks
+    Arp,     // The kernel ARP table in /proc/net/arp
+}
+
+/// Names accepted by "waker import --format"
+pub const FORMAT_NAMES: [&str; 5] = ["waker", "ethers", "dnsmasq", "dhcpd", "arp"];
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "waker" => Some(Format::Waker),
+            "ethers" => Some(Format::Ethers),
+            "dnsmasq" => Some(Format::Dnsmasq),
+            "dhcpd" => Some(Format::Dhcpd),
+            "arp" => Some(Format::Arp),
+            _ => None,
+        }
+    }
+
+    /// Where files of this format usually live on a Linux system
+    pub fn default_path(&self) -> Option<&'static str> {
+        match self {
+            Format::Waker => None,
+            Format::Ethers => Some("/etc/ethers"),
+            Format::Dnsmasq => Some("/var/lib/misc/dnsmasq.leases"),
+            Format::Dhcpd => Some("/var/lib/dhcp/dhcpd.leases"),
+            Format::Arp => Some("/proc/net/arp"),
+        }
+    }
+}
+
+/// A MAC address with whatever else a foreign file knew about it
+struct Entry {
+    mac: MacAddress,
+    ip: Option<IpAddr>,
+    name: Option<String>,
+}
+
+/// How an imported host relates to the hosts already configured
+#[derive(Debug, PartialEq)]
+pub enum Kind {
+    New,               // Neither its name nor any of its MACs are known
+    Duplicate(String), // Shares a MAC with this configured host, so it is already there
+    Conflict,          // Same name as a configured host, but different MACs
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::New => f.pad("new"),
+            Kind::Duplicate(_) => f.pad("duplicate"),
+            Kind::Conflict => f.pad("conflict"),
+        }
+    }
+}
+
+/// What to do with conflicting hosts
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Fail,      // Refuse to import anything
+    Skip,      // Keep the configured host
+    Overwrite, // Replace the configured host with the imported one
+}
+
+/// Arguments to "waker import"
+pub struct ImportArgs {
+    pub path: Option<PathBuf>,          // Defaults to the usual location of the format
+    pub format: Format,
+    pub policy: Option<ConflictPolicy>, // None picks hosts interactively
+}
+
+/// What an import did, by host name
+#[derive(Default)]
+pub struct ImportSummary {
+    pub added: Vec<String>,
+    pub replaced: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+impl std::fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Added {}, replaced {}, skipped {}",
+            self.added.len(),
+            self.replaced.len(),
+            self.skipped.len()
+        )
+    }
+}
+
+/// Loads another config file to import from. Unlike Machines::from_file, a missing file
+/// is an error rather than being created.
+pub fn load(path: &PathBuf) -> Result<Machines, WakerError> {
+    if !path.is_file() {
+        return Err(WakerError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} does not exist", path.display()),
+        )));
+    }
+    Machines::from_file(path)
+}
+
+/// Loads hosts from a file in the given format. Foreign formats only know a MAC with an optional
+/// IP address and name per entry, entries without a name are named after their IP or MAC.
+pub fn load_as(format: Format, path: &Path) -> Result<Machines, WakerError> {
+    if format == Format::Waker {
+        return load(&path.to_path_buf());
+    }
+    let text = std::fs::read_to_string(path)
+        .map_err(|what| WakerError::Io(std::io::Error::new(what.kind(), format!("{}: {}", path.display(), what))))?;
+    let entries = match format {
+        Format::Ethers => parse_ethers(&text),
+        Format::Dnsmasq => parse_dnsmasq(&text),
+        Format::Dhcpd => parse_dhcpd(&text),
+        Format::Arp => parse_arp(&text),
+        Format::Waker => Vec::new(),
+    };
+    Ok(collect_hosts(entries))
+}
+
+/// Turns entries into hosts. The last entry for a MAC wins, since leases are appended as they
+/// change, and entries sharing a name become one host with several MACs.
+fn collect_hosts(entries: Vec<Entry>) -> Machines {
+    let mut latest: Vec<Entry> = Vec::new();
+    for entry in entries {
+        match latest.iter_mut().find(|seen| seen.mac == entry.mac) {
+            Some(seen) => *seen = entry,
+            None => latest.push(entry),
+        }
+    }
+
+    let mut machines = Machines::new();
+    for entry in latest {
+        let name = match (&entry.name, &entry.ip) {
+            (Some(name), _) => name.clone(),
+            (None, Some(ip)) => ip.to_string(),
+            (None, None) => entry.mac.to_string(),
+        };
+        match machines.find_mut(&name) {
+            Some(host) => {
+                host.macs.push(entry.mac);
+                if let Some(ip) = entry.ip {
+                    if !host.ips.contains(&ip) {
+                        host.ips.push(ip);
+                    }
+                }
+            }
+            None => {
+                machines.add(&name, entry.mac, entry.ip);
+            }
+        }
+    }
+    machines
+}
+
+/// Foreign files are written by other tools, so MACs are read leniently (i.e. 8:0:20:1:2:3)
+fn foreign_mac(mac_str: &str) -> Option<MacAddress> {
+    sanitizers::parse_mac(mac_str, Mode::Lenient).ok()
+}
+
+fn foreign_ip(ip_str: &str) -> Option<IpAddr> {
+    sanitizers::parse_ip(ip_str, Mode::Strict).ok()
+}
+
+/// Lines without the expected fields are skipped rather than failing the whole import
+fn parse_ethers(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [mac, target, ..] = fields.as_slice() {
+            if let Some(mac) = foreign_mac(mac) {
+                let ip = foreign_ip(target);
+                let name = match ip {
+                    Some(_) => None,
+                    None => Some(target.to_string()),
+                };
+                entries.push(Entry { mac, ip, name });
+            }
+        }
+    }
+    entries
+}
+
+fn parse_dnsmasq(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // IPv6 leases and the "duid" line carry no MAC and are skipped here
+        if let [_expiry, mac, ip, name, ..] = fields.as_slice() {
+            if let Some(mac) = foreign_mac(mac) {
+                let name = match *name {
+                    "*" => None,
+                    name => Some(name.to_string()),
+                };
+                entries.push(Entry { mac, ip: foreign_ip(ip), name });
+            }
+        }
+    }
+    entries
+}
+
+fn parse_dhcpd(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut ip: Option<IpAddr> = None;
+    let mut mac: Option<MacAddress> = None;
+    let mut name: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim().trim_end_matches(';');
+        if let Some(rest) = line.strip_prefix("lease ") {
+            ip = rest.split_whitespace().next().and_then(foreign_ip);
+            mac = None;
+            name = None;
+        } else if let Some(rest) = line.strip_prefix("hardware ethernet ") {
+            mac = foreign_mac(rest);
+        } else if let Some(rest) = line.strip_prefix("client-hostname ") {
+            name = Some(rest.trim_matches('"').to_string()).filter(|name| !name.is_empty());
+        } else if line == "}" {
+            if let Some(mac) = mac.take() {
+                entries.push(Entry { mac, ip: ip.take(), name: name.take() });
+            }
+        }
+    }
+    entries
+}
+
+fn parse_arp(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    // The first line is a header
+    for line in text.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [ip, _hw_type, flags, mac, ..] = fields.as_slice() {
+            // Incomplete entries have no flags and an all zero MAC
+            if *flags == "0x0" {
+                continue;
+            }
+            match foreign_mac(mac) {
+                Some(mac) if *mac.bytes() != [0; 6] => entries.push(Entry { mac, ip: foreign_ip(ip), name: None }),
+                _ => {}
+            }
+        }
+    }
+    entries
+}
+
+/// Works out how an incoming host relates to the configured ones
+pub fn classify(machines: &Machines, host: &Host) -> Kind {
+    classify_among(&machines.list, host)
+}
+
+fn classify_among(list: &[Host], host: &Host) -> Kind {
+    if let Some(existing) = list.iter().find(|existing| existing.name == host.name) {
+        return match existing.macs.iter().any(|mac| host.macs.contains(mac)) {
+            true => Kind::Duplicate(existing.name.clone()),
+            false => Kind::Conflict,
+        };
+    }
+    match list.iter().find(|existing| existing.macs.iter().any(|mac| host.macs.contains(mac))) {
+        Some(existing) => Kind::Duplicate(existing.name.clone()),
+        None => Kind::New,
+    }
+}
+
+/// One line per incoming host with its index and how it relates to the configured hosts
+pub fn listing(machines: &Machines, incoming: &Machines) -> String {
+    let lines: Vec<String> = incoming
+        .list
+        .iter()
+        .enumerate()
+        .map(|(index, host)| match classify(machines, host) {
+            Kind::Duplicate(existing) if existing != host.name => {
+                format!("{:<3}{:<10}{} (as {})", index, Kind::Duplicate(existing.clone()), host, existing)
+            }
+            kind => format!("{:<3}{:<10}{}", index, kind, host),
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Merges the selected incoming hosts into the configured ones. Duplicates are always skipped,
+/// conflicts are handled according to the policy. Each host is classified against the hosts
+/// merged so far, so a selected host that repeats an earlier one is a duplicate or conflict of
+/// it. Hosts named like a waker subcommand are skipped, or fail the import with
+/// ConflictPolicy::Fail. Group memberships of imported hosts are carried over. Nothing is
+/// imported if any selected host conflicts with ConflictPolicy::Fail, conflicts with another
+/// imported host, or would share a MAC with another host when overwriting.
+pub fn merge(
+    machines: &mut Machines,
+    incoming: &Machines,
+    selected: &[usize],
+    policy: ConflictPolicy,
+) -> Result<ImportSummary, WakerError> {
+    let mut merged = machines.list.clone();
+    let mut summary = ImportSummary::default();
+    for index in selected {
+        let host = incoming
+            .list
+            .get(*index)
+            .ok_or_else(|| WakerError::Parse(format!("No host with index {} to import", index)))?;
+        if let Err(e) = Machines::check_name(&host.name) {
+            if policy == ConflictPolicy::Fail {
+                return Err(e);
+            }
+            summary.skipped.push(host.name.clone());
+            continue;
+        }
+        match (classify_among(&merged, host), policy) {
+            (Kind::New, _) => {
+                merged.push(host.clone());
+                summary.added.push(host.name.clone());
+            }
+            (Kind::Conflict, _) if summary.added.contains(&host.name) || summary.replaced.contains(&host.name) => {
+                return Err(WakerError::Config(format!(
+                    "{} is imported twice with different MACs, select only one of them",
+                    host.name
+                )));
+            }
+            (Kind::Conflict, ConflictPolicy::Fail) => {
+                return Err(WakerError::Config(format!(
+                    "{} conflicts with a configured host, skip or overwrite conflicts to import",
+                    host.name
+                )));
+            }
+            (Kind::Conflict, ConflictPolicy::Overwrite) => {
+                let shared = merged
+                    .iter()
+                    .find(|other| other.name != host.name && other.macs.iter().any(|mac| host.macs.contains(mac)));
+                if let Some(other) = shared {
+                    return Err(WakerError::Config(format!(
+                        "{} can not be overwritten, it would share a MAC address with {}",
+                        host.name, other.name
+                    )));
+                }
+                if let Some(existing) = merged.iter_mut().find(|existing| existing.name == host.name) {
+                    *existing = host.clone();
+                }
+                summary.replaced.push(host.name.clone());
+            }
+            _ => summary.skipped.push(host.name.clone()),
+        }
+    }
+
+    machines.list = merged;
+    for name in summary.added.iter().chain(&summary.replaced) {
+        for group in incoming.groups_of(name) {
+            machines.group_add(group, name)?;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::demo_machines;
+
+    fn incoming() -> Machines {
+        let mut m = Machines::new();
+        m.add("laptop", "AA:BB:CC:DD:EE:06".parse().unwrap(), None);
+        m.add("storage", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        m.add("directed", "AA:BB:CC:DD:EE:07".parse().unwrap(), None);
+        m.group_add("office", "laptop").unwrap();
+        m
+    }
+
+    fn names(machines: &Machines) -> Vec<String> {
+        machines.list.iter().map(|host| host.to_string()).collect()
+    }
+
+    #[test]
+    fn ethers() {
+        let text = "# Comment\n8:0:20:1:2:3 sun\naa:bb:cc:dd:ee:01\t10.0.0.1 # Trailing comment\nnot-a-mac oops\n";
+        assert_eq!(
+            vec![
+                r#"sun              ["08:00:20:01:02:03"] - []"#,
+                r#"10.0.0.1         ["AA:BB:CC:DD:EE:01"] - ["10.0.0.1"]"#,
+            ],
+            names(&collect_hosts(parse_ethers(text)))
+        );
+    }
+
+    #[test]
+    fn dnsmasq_leases() {
+        let text = "1700000000 aa:bb:cc:dd:ee:01 10.0.0.5 nas 01:aa:bb:cc:dd:ee:01\n\
+                    1700000000 aa:bb:cc:dd:ee:02 10.0.0.6 * *\n\
+                    duid 00:01:00:01:2c:aa:bb:cc:dd:ee:ff:00:11\n\
+                    1700000000 1234567 fd00::5 nas 00:01:00:01\n";
+        assert_eq!(
+            vec![
+                r#"nas              ["AA:BB:CC:DD:EE:01"] - ["10.0.0.5"]"#,
+                r#"10.0.0.6         ["AA:BB:CC:DD:EE:02"] - ["10.0.0.6"]"#,
+            ],
+            names(&collect_hosts(parse_dnsmasq(text)))
+        );
+    }
+
+    #[test]
+    fn dhcpd_leases() {
+        let text = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+lease 10.0.0.20 {
+  starts 4 2024/01/04 10:00:00;
+  binding state active;
+  hardware ethernet aa:bb:cc:dd:ee:01;
+  client-hostname "build-1";
+}
+lease 10.0.0.21 {
+  hardware ethernet aa:bb:cc:dd:ee:02;
+}
+lease 10.0.0.22 {
+  hardware ethernet aa:bb:cc:dd:ee:01;
+  client-hostname "build-1";
+}
+"#;
+        // The later lease for the same MAC replaces the earlier one
+        assert_eq!(
+            vec![
+                r#"build-1          ["AA:BB:CC:DD:EE:01"] - ["10.0.0.22"]"#,
+                r#"10.0.0.21        ["AA:BB:CC:DD:EE:02"] - ["10.0.0.21"]"#,
+            ],
+            names(&collect_hosts(parse_dhcpd(text)))
+        );
+    }
+
+    #[test]
+    fn arp_table() {
+        let text = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                    192.168.1.1      0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0\n\
+                    192.168.1.7      0x1         0x0         00:00:00:00:00:00     *        eth0\n";
+        assert_eq!(
+            vec![r#"192.168.1.1      ["AA:BB:CC:DD:EE:01"] - ["192.168.1.1"]"#],
+            names(&collect_hosts(parse_arp(text)))
+        );
+    }
+
+    #[test]
+    fn load_from_path() {
+        let path = PathBuf::from("./DEMO_ETHERS");
+        std::fs::write(&path, "aa:bb:cc:dd:ee:01 nas\naa:bb:cc:dd:ee:02 nas\n").unwrap();
+        let loaded = load_as(Format::Ethers, &path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(1, loaded.list.len());
+        assert_eq!(2, loaded.list[0].macs.len());
+
+        // Foreign hosts are de-duplicated against the configured ones by MAC
+        let mut m = demo_machines();
+        let summary = merge(&mut m, &loaded, &[0], ConflictPolicy::Skip).unwrap();
+        assert_eq!(vec!["nas"], summary.skipped);
+
+        // A foreign host named like a subcommand is not imported
+        let reserved = collect_hosts(parse_ethers("aa:bb:cc:dd:ee:09 wake\n"));
+        assert!(matches!(merge(&mut m, &reserved, &[0], ConflictPolicy::Fail), Err(WakerError::Config(_))));
+        let summary = merge(&mut m, &reserved, &[0], ConflictPolicy::Skip).unwrap();
+        assert_eq!(vec!["wake"], summary.skipped);
+        assert!(matches!(load_as(Format::Arp, Path::new("./NO_SUCH_FILE")), Err(WakerError::Io(_))));
+    }
+
+    #[test]
+    fn classifies_hosts() {
+        let m = demo_machines();
+        let kinds: Vec<Kind> = incoming().list.iter().map(|host| classify(&m, host)).collect();
+        assert_eq!(vec![Kind::New, Kind::Duplicate("plain".to_string()), Kind::Conflict], kinds);
+    }
+
+    #[test]
+    fn merge_policies() {
+        let mut m = demo_machines();
+        let all = [0, 1, 2];
+        assert!(matches!(merge(&mut m, &incoming(), &all, ConflictPolicy::Fail), Err(WakerError::Config(_))));
+        assert_eq!(4, m.list.len());
+
+        let summary = merge(&mut m, &incoming(), &all, ConflictPolicy::Skip).unwrap();
+        assert_eq!(vec!["laptop"], summary.added);
+        assert_eq!(vec!["storage", "directed"], summary.skipped);
+        assert_eq!(vec!["laptop"], m.groups["office"]);
+
+        let mut m = demo_machines();
+        let summary = merge(&mut m, &incoming(), &[2], ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(vec!["directed"], summary.replaced);
+        assert_eq!("AA:BB:CC:DD:EE:07", m.find("directed").unwrap().macs[0].to_string());
+        assert_eq!(4, m.list.len());
+    }
+
+    #[test]
+    fn merged_hosts_are_checked_against_each_other() {
+        let mut twice = incoming();
+        twice.add("laptop", "AA:BB:CC:DD:EE:08".parse().unwrap(), None);
+        twice.add("tablet", "AA:BB:CC:DD:EE:06".parse().unwrap(), None);
+        let mut m = demo_machines();
+        assert!(matches!(merge(&mut m, &twice, &[0, 3], ConflictPolicy::Skip), Err(WakerError::Config(_))));
+        assert_eq!(4, m.list.len());
+
+        // A MAC that is already imported makes the later host a duplicate of it
+        let summary = merge(&mut m, &twice, &[0, 4], ConflictPolicy::Skip).unwrap();
+        assert_eq!(vec!["laptop"], summary.added);
+        assert_eq!(vec!["tablet"], summary.skipped);
+
+        // Overwriting must not leave two hosts with the same MAC
+        let mut shared = Machines::new();
+        shared.add("directed", "AA:BB:CC:DD:EE:03".parse().unwrap(), None);
+        let mut m = demo_machines();
+        assert!(matches!(merge(&mut m, &shared, &[0], ConflictPolicy::Overwrite), Err(WakerError::Config(_))));
+        assert_eq!("AA:BB:CC:DD:EE:02", m.find("directed").unwrap().macs[0].to_string());
+    }
+
+    #[test]
+    fn reserved_names_are_not_imported() {
+        let mut reserved = Machines::new();
+        reserved.add("wake", "AA:BB:CC:DD:EE:09".parse().unwrap(), None);
+        let mut m = demo_machines();
+        assert!(matches!(merge(&mut m, &reserved, &[0], ConflictPolicy::Fail), Err(WakerError::Config(_))));
+        let summary = merge(&mut m, &reserved, &[0], ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(vec!["wake"], summary.skipped);
+        assert_eq!(4, m.list.len());
+    }
+}
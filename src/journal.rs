@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::error::WakerError;
+use crate::host::Host;
+use crate::machines::Machines;
+
+/// Entries kept in the journal, older ones are dropped
+pub const MAX_ENTRIES: usize = 100;
+
+/// Groups by name, as in Machines::groups
+pub type Groups = BTreeMap<String, Vec<String>>;
+
+/// One host before and after an operation, with its position in the list.
+/// Added hosts have no before, deleted ones no after.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Change {
+    pub before: Option<(usize, Host)>,
+    pub after: Option<(usize, Host)>,
+}
+
+impl Change {
+    fn describe(&self) -> String {
+        match (&self.before, &self.after) {
+            (None, Some((_, host))) => format!("added {}", host.name),
+            (Some((_, host)), None) => format!("deleted {}", host.name),
+            (Some((_, before)), Some((_, after))) if before.name != after.name => {
+                format!("renamed {} to {}", before.name, after.name)
+            }
+            (Some((_, host)), Some(_)) => format!("changed {}", host.name),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// Everything one invocation of waker changed in the config
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub time: String,    // UTC, as in backup names
+    pub command: String, // Arguments waker was run with
+    pub changes: Vec<Change>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<(Groups, Groups)>, // Before and after
+}
+
+impl Entry {
+    /// Summary of the changes, for "waker history"
+    pub fn describe(&self) -> String {
+        let mut parts: Vec<String> = self.changes.iter().map(|change| change.describe()).collect();
+        if self.groups.is_some() {
+            parts.push("changed groups".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+/// Operations on a config file, oldest first. The first `position` entries can be undone,
+/// the rest have been undone and can be redone.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Journal {
+    pub entries: Vec<Entry>,
+    pub position: usize,
+}
+
+fn same(a: &Host, b: &Host) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+/// The host level differences between two versions of the list. Hosts are matched by name,
+/// a renamed host is recorded as one change rather than a delete and an add.
+pub fn diff(before: &[Host], after: &[Host]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (index, host) in before.iter().enumerate() {
+        match after.iter().position(|other| other.name == host.name) {
+            Some(other) if same(host, &after[other]) => {}
+            Some(other) => changes.push(Change {
+                before: Some((index, host.clone())),
+                after: Some((other, after[other].clone())),
+            }),
+            None => changes.push(Change { before: Some((index, host.clone())), after: None }),
+        }
+    }
+    for (index, host) in after.iter().enumerate() {
+        if !before.iter().any(|other| other.name == host.name) {
+            changes.push(Change { before: None, after: Some((index, host.clone())) });
+        }
+    }
+
+    // A deleted and an added host that only differ in name are a rename
+    let mut paired: Vec<Change> = Vec::new();
+    for change in changes {
+        let partner = match &change {
+            Change { before: None, after: Some((_, added)) } => paired.iter_mut().find(|other| match other {
+                Change { before: Some((_, deleted)), after: None } => same_but_name(deleted, added),
+                _ => false,
+            }),
+            _ => None,
+        };
+        match partner {
+            Some(other) => other.after = change.after,
+            None => paired.push(change),
+        }
+    }
+    paired
+}
+
+fn same_but_name(a: &Host, b: &Host) -> bool {
+    let mut renamed = b.clone();
+    renamed.name = a.name.clone();
+    same(a, &renamed)
+}
+
+/// Moves the hosts of `changes` from one side to the other. With `forward` the after state is
+/// applied, otherwise the before state. Fails without touching anything if the hosts are not in
+/// the state the change started from, which happens when the config was edited by other means.
+fn apply(machines: &mut Machines, entry: &Entry, forward: bool) -> Result<(), WakerError> {
+    let sides = |change: &Change| match forward {
+        true => (change.before.clone(), change.after.clone()),
+        false => (change.after.clone(), change.before.clone()),
+    };
+    let changed = || WakerError::Config("The config was changed since, refusing to replay the history".to_string());
+
+    let mut list = machines.list.clone();
+    for change in &entry.changes {
+        if let (Some((_, from)), _) = sides(change) {
+            let index = list.iter().position(|host| same(host, &from)).ok_or_else(changed)?;
+            list.remove(index);
+        }
+    }
+    let mut targets: Vec<(usize, Host)> = entry.changes.iter().filter_map(|change| sides(change).1).collect();
+    targets.sort_by_key(|(index, _)| *index);
+    for (index, host) in targets {
+        if list.iter().any(|other| other.name == host.name) {
+            return Err(changed());
+        }
+        list.insert(index.min(list.len()), host);
+    }
+
+    if let Some((before, after)) = &entry.groups {
+        let (from, to) = if forward { (before, after) } else { (after, before) };
+        if &machines.groups != from {
+            return Err(changed());
+        }
+        machines.groups = to.clone();
+    }
+    machines.list = list;
+    Ok(())
+}
+
+impl Journal {
+    /// The journal of a config file lives next to it, as "<config>.journal"
+    pub fn path(config_path: &Path) -> PathBuf {
+        let file_name = config_path.file_name().and_then(|name| name.to_str()).unwrap_or("waker");
+        config_path.with_file_name(format!("{}.journal", file_name))
+    }
+
+    pub fn load(config_path: &Path) -> Result<Journal, WakerError> {
+        let path = Journal::path(config_path);
+        if !path.is_file() {
+            return Ok(Journal::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, config_path: &Path) -> Result<(), WakerError> {
+        config::write_atomic(&Journal::path(config_path), serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Records an operation, forgetting whatever had been undone before it
+    pub fn record(&mut self, entry: Entry) {
+        self.entries.truncate(self.position);
+        self.entries.push(entry);
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.drain(..self.entries.len() - MAX_ENTRIES);
+        }
+        self.position = self.entries.len();
+    }
+
+    /// Reverts the last operation that has not been undone yet, returning it
+    pub fn undo(&mut self, machines: &mut Machines) -> Result<&Entry, WakerError> {
+        if self.position == 0 {
+            return Err(WakerError::Config("Nothing to undo".to_string()));
+        }
+        apply(machines, &self.entries[self.position - 1], false)?;
+        self.position -= 1;
+        Ok(&self.entries[self.position])
+    }
+
+    /// Replays the last undone operation, returning it
+    pub fn redo(&mut self, machines: &mut Machines) -> Result<&Entry, WakerError> {
+        if self.position == self.entries.len() {
+            return Err(WakerError::Config("Nothing to redo".to_string()));
+        }
+        apply(machines, &self.entries[self.position], true)?;
+        self.position += 1;
+        Ok(&self.entries[self.position - 1])
+    }
+
+    /// One line per operation, oldest first. Undone operations are marked as such.
+    pub fn listing(&self) -> String {
+        let lines: Vec<String> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let state = if index < self.position { "" } else { " (undone)" };
+                format!("{:<3}{}  waker {}: {}{}", index, entry.time, entry.command, entry.describe(), state)
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(before: &Machines, after: &Machines) -> Entry {
+        Entry {
+            time: String::new(),
+            command: String::new(),
+            changes: diff(&before.list, &after.list),
+            groups: None,
+        }
+    }
+
+    fn names(machines: &Machines) -> Vec<&str> {
+        machines.list.iter().map(|host| host.name.as_str()).collect()
+    }
+
+    #[test]
+    fn diff_hosts() {
+        let mut before = Machines::new();
+        before.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        before.add("desktop", "AA:BB:CC:DD:EE:02".parse().unwrap(), None);
+        before.add("laptop", "AA:BB:CC:DD:EE:03".parse().unwrap(), None);
+        let mut after = Machines::new();
+        after.list = before.list.clone();
+        after.list.remove(0);
+        after.list[0].name = "workstation".to_string();
+        after.list[1].port = Some(7);
+        after.add("printer", "AA:BB:CC:DD:EE:04".parse().unwrap(), None);
+
+        let descriptions: Vec<String> = diff(&before.list, &after.list).iter().map(|change| change.describe()).collect();
+        assert_eq!(vec!["deleted nas", "renamed desktop to workstation", "changed laptop", "added printer"], descriptions);
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut m = Machines::new();
+        m.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        m.add("desktop", "AA:BB:CC:DD:EE:02".parse().unwrap(), None);
+        m.add("laptop", "AA:BB:CC:DD:EE:03".parse().unwrap(), None);
+        let mut edited = Machines::new();
+        edited.list = m.list.clone();
+        edited.remove("desktop").unwrap();
+        edited.find_mut("laptop").unwrap().port = Some(7);
+
+        let mut journal = Journal::default();
+        journal.record(entry(&m, &edited));
+        assert_eq!(1, journal.position);
+
+        journal.undo(&mut edited).unwrap();
+        assert_eq!(vec!["nas", "desktop", "laptop"], names(&edited));
+        assert_eq!(None, edited.find("laptop").unwrap().port);
+        assert!(journal.undo(&mut edited).is_err());
+
+        journal.redo(&mut edited).unwrap();
+        assert_eq!(vec!["nas", "laptop"], names(&edited));
+        assert_eq!(Some(7), edited.find("laptop").unwrap().port);
+        assert!(journal.redo(&mut edited).is_err());
+
+        // A host changed behind the journal's back blocks undoing
+        edited.find_mut("laptop").unwrap().port = Some(9);
+        assert!(matches!(journal.undo(&mut edited), Err(WakerError::Config(_))));
+        assert_eq!(1, journal.position);
+
+        // Recording after an undo drops what could have been redone
+        edited.find_mut("laptop").unwrap().port = Some(7);
+        journal.undo(&mut edited).unwrap();
+        journal.record(entry(&m, &m));
+        assert_eq!(1, journal.entries.len());
+        assert!(journal.listing().starts_with("0  "));
+    }
+}
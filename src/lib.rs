@@ -0,0 +1,55 @@
+//! Wake on LAN, with a configuration of named hosts.
+//!
+//! This is the library behind the waker command line tool. It has no interactive parts and does
+//! not depend on clap, so other programs can build magic packets, load and edit the config file
+//! and wake hosts with it. Turn off the default "cli" feature to leave out the tool's
+//! dependencies:
+//!
+//! ```toml
+//! waker = { version = "0.1", default-features = false }
+//! ```
+//!
+//! ```no_run
+//! use waker::{Machines, Selection};
+//!
+//! let machines = Machines::from_file(&"waker.json".into())?;
+//! let selection = Selection { targets: vec!["nas".to_string()], ..Default::default() };
+//! for host in machines.select(&selection)? {
+//!     host.wake(None)?;
+//! }
+//! # Ok::<(), waker::WakerError>(())
+//! ```
+
+pub mod backup; // Rotated copies of the config file, taken before every change, and restoring them
+pub mod commands; // Non-interactive add, set and friends, validated with the strict sanitizers
+pub mod config; // Config file discovery and the JSON, TOML and YAML formats
+pub mod error; // The WakerError type, returned by everything that can fail
+pub mod ethernet; // Raw Ethernet frames (EtherType 0x0842) for layer 2 wakes
+pub mod export; // Renders the configured hosts as CSV, ethers, hosts or Ansible inventories
+#[cfg(test)]
+mod fixtures; // Demo hosts shared by the unit tests
+pub mod host; // The actual Host struct
+pub mod import; // Merging hosts from other config files into ours
+pub mod journal; // History of the changes made to the config, for undo and redo
+pub mod mac; // The MacAddress type
+pub mod machines; // Struct that holds a vec of Hosts, as well as operations on those
+pub mod migrate; // Upgrades config files written in older layouts
+#[cfg(feature = "async")]
+pub mod nonblocking; // Async wakes and probes on tokio
+pub mod pacing; // How often magic packets are repeated, how far apart and to which addresses
+pub mod packet; // The actual magic packet struct, with wake methods e.t.c.
+pub mod sanitizers; // Functions that sanitizes MAC and IP addresses
+pub mod status; // Pings hosts to find out which ones are already awake
+pub mod transport; // How magic packets are sent, and a mock that records them instead
+pub mod wake; // Waking several hosts at once, optionally waiting for them to come up
+
+pub use config::{ConfigFormat, ConfigLock};
+pub use error::WakerError;
+pub use host::Host;
+pub use mac::MacAddress;
+pub use machines::{Machines, Selection, WakeReport};
+pub use pacing::Pacing;
+pub use packet::MagicPacket;
+pub use status::{ProbeOptions, Status, WaitOptions};
+pub use transport::{MockTransport, Route, SystemTransport, Transport};
+pub use wake::{wake_hosts, wake_hosts_with, WakeOptions};
@@ -0,0 +1,84 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::WakerError;
+use crate::sanitizers::{self, Mode};
+
+/// A 48-bit hardware address.
+/// Serialized as its canonical string form, upper case and colon separated (XX:XX:XX:XX:XX:XX)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    pub fn new(bytes: [u8; 6]) -> MacAddress {
+        MacAddress(bytes)
+    }
+
+    pub fn bytes(&self) -> &[u8; 6] {
+        &self.0
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = WakerError;
+
+    /// Accepts any of the notations the strict sanitizer does
+    fn from_str(mac_str: &str) -> Result<Self, Self::Err> {
+        sanitizers::parse_mac(mac_str, Mode::Strict)
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = WakerError;
+
+    fn try_from(mac_str: String) -> Result<Self, Self::Error> {
+        mac_str.parse()
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac: MacAddress) -> String {
+        mac.to_string()
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", b[0], b[1], b[2], b[3], b[4], b[5])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let mac: MacAddress = "aa:bb:cc:dd:ee:0f".parse().unwrap();
+        assert_eq!(&[0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x0F], mac.bytes());
+        assert_eq!("AA:BB:CC:DD:EE:0F", mac.to_string());
+        assert_eq!(mac, "AA-BB-CC-DD-EE-0F".parse().unwrap());
+        assert_eq!(mac, "aabbccddee0f".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("aa:bb:cc:dd:ee".parse::<MacAddress>().is_err());
+        assert!("aa:bb:cc:dd:ee:ff:00".parse::<MacAddress>().is_err());
+        assert!("zz:bb:cc:dd:ee:ff".parse::<MacAddress>().is_err());
+        assert!("".parse::<MacAddress>().is_err());
+    }
+
+    #[test]
+    fn serde_as_string() {
+        let mac = MacAddress::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        assert_eq!("\"01:02:03:04:05:06\"", serde_json::to_string(&mac).unwrap());
+        assert_eq!(mac, serde_json::from_str("\"01-02-03-04-05-06\"").unwrap());
+        assert!(serde_json::from_str::<MacAddress>("\"not a mac\"").is_err());
+    }
+}
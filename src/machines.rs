@@ -1,90 +1,545 @@
 // use std::{fs::{File, OpenOptions, metadata}, io::{Read, Write}, path::{Path, PathBuf}};
-use std::error::Error;
-use std::{
-    fs::{File, OpenOptions},
-    io::Write,
-    path::PathBuf,
-};
+use std::path::{Path, PathBuf};
 
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::config::{self, ConfigFormat, Layer, SystemLayer};
+use crate::error::WakerError;
 use crate::host::Host;
-use crate::packet::MagicPacket;
+use crate::mac::MacAddress;
+use crate::migrate;
+use crate::pacing::Pacing;
+use crate::sanitizers::{self, Mode};
+use crate::status::Status;
+use crate::transport::{SystemTransport, Transport};
 use serde::{Deserialize, Serialize};
 
+/// Outcome of waking a batch of hosts, one entry per host in the order they were woken.
+/// When waiting for the hosts to come up, a success holds how long that took.
+pub struct WakeReport {
+    pub results: Vec<(String, Result<Option<Duration>, WakerError>)>,
+}
+
+impl WakeReport {
+    /// Collapses the report into the first failure, if any host failed to wake
+    pub fn into_result(self) -> Result<(), WakerError> {
+        for (_, result) in self.results {
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// Which hosts to wake, as given on the command line
+#[derive(Default)]
+pub struct Selection {
+    pub targets: Vec<String>, // Names, globs, indexes or MACs, see Machines::resolve
+    pub tags: Vec<String>,    // Every host carrying one of these tags
+    pub groups: Vec<String>,  // Every member of these groups
+}
+
+/// Subcommands of the waker tool. "waker add" runs the subcommand, so a host named like one
+/// could not be woken by its bare name.
+pub const RESERVED_NAMES: [&str; 15] = [
+    "add", "backup", "config", "export", "group", "help", "history", "import", "redo", "remove", "rename", "restore",
+    "set", "undo", "wake",
+];
+
 // Possibly rename to HostList
 #[derive(Serialize, Deserialize)]
 pub struct Machines {
+    /// Layout of the config file, see migrate.rs
+    #[serde(default)]
+    pub version: u64,
     pub list: Vec<Host>,
+    /// Named sets of hosts, by host name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Pacing of the hosts that do not set their own
+    #[serde(default, skip_serializing_if = "Pacing::is_unset")]
+    pub pacing: Pacing,
+    /// Version of the layout this was upgraded from when it was loaded, if it was older
+    #[serde(skip)]
+    pub migrated_from: Option<u64>,
+    /// The system config merged into list and groups, never written back
+    #[serde(skip)]
+    pub system: Option<SystemLayer>,
+    /// Hosts that were merged in from the system config, rather than read from the per-user one.
+    /// System hosts can not be renamed or removed, so their names stay valid.
+    #[serde(skip)]
+    pub system_hosts: Vec<String>,
+    /// Group members that were merged in from the system config, by group
+    #[serde(skip)]
+    pub system_members: BTreeMap<String, Vec<String>>,
 }
 
 impl Machines {
     pub fn new() -> Machines {
         Machines {
+            version: migrate::CURRENT_VERSION,
             list: Vec::<Host>::new(),
+            groups: BTreeMap::new(),
+            pacing: Pacing::default(),
+            migrated_from: None,
+            system: None,
+            system_hosts: Vec::new(),
+            system_members: BTreeMap::new(),
+        }
+    }
+
+    /// Merges the hosts and groups of the system config under these ones. Hosts named in both
+    /// keep the per-user version, system hosts are listed after the per-user ones.
+    pub fn merge_system_layer(&mut self, layer: SystemLayer) {
+        for host in &layer.hosts {
+            if self.find(&host.name).is_none() {
+                self.list.push(host.clone());
+                self.system_hosts.push(host.name.clone());
+            }
+        }
+        for (group, members) in &layer.groups {
+            let merged = self.groups.entry(group.clone()).or_default();
+            for member in members {
+                if !merged.contains(member) {
+                    merged.push(member.clone());
+                    self.system_members.entry(group.clone()).or_default().push(member.clone());
+                }
+            }
+        }
+        self.system = Some(layer);
+    }
+
+    /// Which config a host comes from. A host merged in from the system config counts as a
+    /// per-user one once it is changed, as the change is stored in the per-user config.
+    pub fn layer_of(&self, host: &Host) -> Layer {
+        if !self.system_hosts.contains(&host.name) {
+            return Layer::User;
+        }
+        let original = self.system.as_ref().and_then(|system| system.hosts.iter().find(|s| s.name == host.name));
+        match original {
+            Some(original) if original == host => Layer::System,
+            _ => Layer::User,
         }
     }
 
+    /// The part of the configuration that belongs in the per-user config file
+    pub fn user_layer(&self) -> Machines {
+        let mut user = Machines::new();
+        user.version = self.version;
+        user.pacing = self.pacing;
+        user.list = self.list.iter().filter(|host| self.layer_of(host) == Layer::User).cloned().collect();
+        for (group, members) in &self.groups {
+            let system_members = self.system_members.get(group);
+            let members: Vec<String> = members
+                .iter()
+                .filter(|member| !system_members.is_some_and(|system_members| system_members.contains(member)))
+                .cloned()
+                .collect();
+            if !members.is_empty() {
+                user.groups.insert(group.clone(), members);
+            }
+        }
+        user
+    }
+
+    /// Hosts of the system config can be changed, which stores a copy in the per-user config,
+    /// but they can not be removed or renamed
+    pub fn check_removable(&self, name: &str) -> Result<(), WakerError> {
+        if let Some(system) = &self.system {
+            if system.hosts.iter().any(|host| host.name == name) {
+                return Err(WakerError::Config(format!(
+                    "\"{}\" comes from {}, which is read only",
+                    name,
+                    system.path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
     // This one needs refactoring...
     /// Add new host to the list, taking a name and a mac, with an optional IP-adress
-    pub fn add(&mut self, name: &str, mac_addr: &str, ip_addr: Option<String>) {
+    /// Returns a reference to the newly added host, so further fields can be set on it
+    pub fn add(&mut self, name: &str, mac_addr: MacAddress, ip_addr: Option<IpAddr>) -> &mut Host {
         match ip_addr {
             Some(ip_addr) => {
-                self.list.push(Host::new(name.to_string(), mac_addr.to_string(), ip_addr.to_string()))
+                self.list.push(Host::new(name, mac_addr, ip_addr))
             }
             None => {
                 self.list.push(Host {
                     name: name.to_string(),
-                    macs: vec![mac_addr.to_string()],
+                    macs: vec![mac_addr],
                     ips: vec![],
+                    ..Default::default()
                 });
             }
         }
+        self.list.last_mut().unwrap()
+    }
+
+    /// Looks up a host by its exact name
+    pub fn find(&self, name: &str) -> Option<&Host> {
+        self.list.iter().find(|host| host.name == name)
+    }
+
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut Host> {
+        self.list.iter_mut().find(|host| host.name == name)
+    }
+
+    /// Removes the named host from the list and from every group, returning it
+    pub fn remove(&mut self, name: &str) -> Result<Host, WakerError> {
+        match self.list.iter().position(|host| host.name == name) {
+            Some(index) => {
+                self.check_removable(name)?;
+                self.rename_members(name, None);
+                Ok(self.list.remove(index))
+            }
+            None => Err(WakerError::Config(format!("No host named \"{}\"", name))),
+        }
+    }
+
+    /// Refuses names that clash with a subcommand of the waker tool
+    pub fn check_name(name: &str) -> Result<(), WakerError> {
+        if RESERVED_NAMES.contains(&name) {
+            return Err(WakerError::Config(format!(
+                "\"{}\" is a waker subcommand, a host of that name could only be woken with \"waker wake {}\"",
+                name, name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Renames a host, refusing to create duplicate names
+    pub fn rename(&mut self, old_name: &str, new_name: &str) -> Result<(), WakerError> {
+        let new_name = new_name.trim();
+        if new_name.is_empty() {
+            return Err(WakerError::Parse("A host needs a name".to_string()));
+        }
+        Machines::check_name(new_name)?;
+        if old_name != new_name && self.find(new_name).is_some() {
+            return Err(WakerError::Config(format!("A host named \"{}\" already exists", new_name)));
+        }
+        if self.find(old_name).is_none() {
+            return Err(WakerError::Config(format!("No host named \"{}\"", old_name)));
+        }
+        self.check_removable(old_name)?;
+        let host = self.find_mut(old_name).unwrap();
+        host.name = new_name.to_string();
+        self.rename_members(old_name, Some(new_name));
+        Ok(())
+    }
+
+    /// Keeps group memberships in step with a host that was renamed, or removed if new_name is None
+    pub fn rename_members(&mut self, old_name: &str, new_name: Option<&str>) {
+        for members in self.groups.values_mut() {
+            match new_name {
+                Some(new_name) => members.iter_mut().filter(|m| *m == old_name).for_each(|m| *m = new_name.to_string()),
+                None => members.retain(|m| m != old_name),
+            }
+        }
+    }
+
+    /// Hosts carrying the given tag
+    pub fn tagged(&self, tag: &str) -> Result<Vec<&Host>, WakerError> {
+        let hosts: Vec<&Host> = self.list.iter().filter(|host| host.tags.iter().any(|t| t == tag)).collect();
+        if hosts.is_empty() {
+            return Err(WakerError::Config(format!("No host is tagged \"{}\"", tag)));
+        }
+        Ok(hosts)
+    }
+
+    /// Members of the named group, in list order
+    pub fn group(&self, group: &str) -> Result<Vec<&Host>, WakerError> {
+        let members = self
+            .groups
+            .get(group)
+            .ok_or_else(|| WakerError::Config(format!("No group named \"{}\"", group)))?;
+        Ok(self.list.iter().filter(|host| members.contains(&host.name)).collect())
+    }
+
+    /// Adds a host to a group, creating the group if needed
+    pub fn group_add(&mut self, group: &str, name: &str) -> Result<(), WakerError> {
+        let group = group.trim();
+        if group.is_empty() {
+            return Err(WakerError::Parse("A group needs a name".to_string()));
+        }
+        if self.find(name).is_none() {
+            return Err(WakerError::Config(format!("No host named \"{}\"", name)));
+        }
+        let members = self.groups.entry(group.to_string()).or_default();
+        if !members.iter().any(|m| m == name) {
+            members.push(name.to_string());
+        }
+        Ok(())
     }
 
-    /// Parses the Machine object from a json file
-    pub fn from_json_file(json_path: &PathBuf) -> Result<Machines, Box<dyn Error>> {
+    /// Takes a host out of a group. The group itself is kept, even when it becomes empty.
+    pub fn group_remove(&mut self, group: &str, name: &str) -> Result<(), WakerError> {
+        let members = self
+            .groups
+            .get_mut(group)
+            .ok_or_else(|| WakerError::Config(format!("No group named \"{}\"", group)))?;
+        let before = members.len();
+        members.retain(|m| m != name);
+        if members.len() == before {
+            return Err(WakerError::Config(format!("{} is not in group {}", name, group)));
+        }
+        Ok(())
+    }
+
+    /// Deletes a group, leaving its members alone
+    pub fn group_delete(&mut self, group: &str) -> Result<(), WakerError> {
+        match self.groups.remove(group) {
+            Some(_) => Ok(()),
+            None => Err(WakerError::Config(format!("No group named \"{}\"", group))),
+        }
+    }
+
+    /// Names of the groups the host belongs to
+    pub fn groups_of(&self, name: &str) -> Vec<&str> {
+        self.groups
+            .iter()
+            .filter(|(_, members)| members.iter().any(|m| m == name))
+            .map(|(group, _)| group.as_str())
+            .collect()
+    }
+
+    /// Every group with its members, one per line
+    pub fn group_listing(&self) -> String {
+        let lines: Vec<String> = self
+            .groups
+            .iter()
+            .map(|(group, members)| format!("{:<16} {}", group, members.join(", ")))
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Parses the Machine object from a config file, in the format matching its extension
+    pub fn from_file(config_path: &PathBuf) -> Result<Machines, WakerError> {
+        let format = ConfigFormat::from_path(config_path);
         let machines: Machines;
-        if json_path.exists() || json_path.is_file() {
-            let json: String = std::fs::read_to_string(&json_path)?.parse()?;
-            machines = serde_json::from_str(&json)?;
+        if config_path.exists() || config_path.is_file() {
+            let text: String = std::fs::read_to_string(config_path)?;
+            machines = format.deserialize(&text)?;
         } else {
             machines = Machines::new();
-            let serialized = serde_json::to_string_pretty(&machines)?;
-            let mut file = File::create(&json_path)?;
-            file.write_all(&serialized.as_bytes())?;
-            std::fs::write(&json_path, &serialized)?;
+            config::write_atomic(config_path, format.serialize(&machines)?.as_bytes())?;
         }
         Ok(machines)
     }
 
     /// Creates file and dumps a skeleton config into it
-    pub fn create_skeleton_config(file: &PathBuf) -> Result<(), Box<dyn Error>> {
-        std::fs::File::create(&file)?;
+    pub fn create_skeleton_config(file: &Path) -> Result<(), WakerError> {
         let skel_machines = Machines::new();
         skel_machines.dump(file)?;
         return Ok(());
     }
 
-    /// Dump this struct in json format. Will NOT create file.
-    pub fn dump(&self, json_path: &PathBuf) -> Result<bool, Box<dyn Error>> {
-        let serialized = serde_json::to_string_pretty(&self)?;
-        let mut file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&json_path)?;
-        file.write_all(&serialized.as_bytes())?;
+    /// Dump this struct in the format matching the file extension, leaving out the system config.
+    /// The file is replaced atomically, so a crash leaves either the old or the new config.
+    pub fn dump(&self, config_path: &Path) -> Result<bool, WakerError> {
+        let serialized = ConfigFormat::from_path(config_path).serialize(&self.user_layer())?;
+        config::write_atomic(config_path, serialized.as_bytes())?;
         Ok(true)
     }
 
-    /// Attempts to wake all configured hosts via the default os-provided network interface
-    pub fn wakeall(&self) {
-        for host in &self.list {
-            for mac_str in &host.macs {
-                MagicPacket::from_str(mac_str).unwrap().send().unwrap();
+    /// Same listing as the Display implementation, with a status column after the index.
+    /// If a system config is merged in, a column telling which config each host comes from follows.
+    pub fn with_status(&self, statuses: &[Status]) -> String {
+        let lines: Vec<String> = self
+            .list
+            .iter()
+            .zip(statuses)
+            .enumerate()
+            .map(|(index, (host, status))| match self.system {
+                Some(_) => format!("{:<3}{:<8}{:<8}{}", index, status, self.layer_of(host), host),
+                None => format!("{:<3}{:<8}{}", index, status, host),
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Resolves targets, tags and groups into one list of hosts to wake, without duplicates
+    pub fn select(&self, selection: &Selection) -> Result<Vec<Host>, WakerError> {
+        let mut selected = self.resolve(&selection.targets)?;
+        let mut extra: Vec<&Host> = Vec::new();
+        for tag in &selection.tags {
+            extra.extend(self.tagged(tag)?);
+        }
+        for group in &selection.groups {
+            extra.extend(self.group(group)?);
+        }
+        for host in extra {
+            if !selected.iter().any(|s| s.name == host.name) {
+                selected.push(host.clone());
             }
         }
+        Ok(selected)
+    }
+
+    /// Resolves command line targets into hosts to wake. Each target is tried as an exact name,
+    /// an index into the list, a MAC address and finally a shell style glob (*, ? and [abc]).
+    /// A MAC that is not configured becomes an unnamed host of its own.
+    /// Fails if a target matches nothing, or matches different hosts in different ways.
+    pub fn resolve(&self, targets: &[String]) -> Result<Vec<Host>, WakerError> {
+        let mut resolved: Vec<Host> = Vec::new();
+        for target in targets {
+            for host in self.resolve_one(target)? {
+                if !resolved.iter().any(|r| r.name == host.name && r.macs == host.macs) {
+                    resolved.push(host);
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn resolve_one(&self, target: &str) -> Result<Vec<Host>, WakerError> {
+        let by_name = self.list.iter().position(|host| host.name == target);
+        let by_index = target.parse::<usize>().ok().filter(|index| *index < self.list.len());
+        if let (Some(name_index), Some(index)) = (by_name, by_index) {
+            if name_index != index {
+                return Err(WakerError::Parse(format!(
+                    "\"{}\" is ambiguous, it is both the name of host {} and the index of {}",
+                    target, name_index, self.list[index].name
+                )));
+            }
+        }
+        if let Some(index) = by_name.or(by_index) {
+            return Ok(vec![self.list[index].clone()]);
+        }
+
+        if let Ok(mac) = sanitizers::parse_mac(target, Mode::Strict) {
+            let owners: Vec<&Host> = self.list.iter().filter(|host| host.macs.contains(&mac)).collect();
+            return match owners.as_slice() {
+                [] => Ok(vec![Host {
+                    name: mac.to_string(),
+                    macs: vec![mac],
+                    ..Default::default()
+                }]),
+                [owner] => Ok(vec![(*owner).clone()]),
+                _ => Err(WakerError::Parse(format!(
+                    "{} is ambiguous, it belongs to {}",
+                    mac,
+                    owners.iter().map(|host| host.name.as_str()).collect::<Vec<&str>>().join(", ")
+                ))),
+            };
+        }
+
+        if target.contains(['*', '?', '[']) {
+            let matches: Vec<Host> = self.list.iter().filter(|host| glob_match(target, &host.name)).cloned().collect();
+            if !matches.is_empty() {
+                return Ok(matches);
+            }
+        }
+        Err(WakerError::Config(format!("No host matches \"{}\"", target)))
+    }
+
+    /// Attempts to wake all configured hosts via the default os-provided network interface,
+    /// or as raw Ethernet frames on the given interface.
+    /// A host that fails to wake does not stop the rest from being woken.
+    pub fn wakeall(&self, ethernet: Option<&str>) -> WakeReport {
+        self.wakeall_with(&SystemTransport, ethernet)
+    }
+
+    /// Same as wakeall, handing the packets to the given transport
+    pub fn wakeall_with(&self, transport: &dyn Transport, ethernet: Option<&str>) -> WakeReport {
+        let results = self
+            .list
+            .iter()
+            .map(|host| {
+                let pacing = host.pacing().or(self.pacing);
+                (host.name.clone(), host.wake_paced(transport, ethernet, &pacing).map(|_| None))
+            })
+            .collect();
+        WakeReport { results }
+    }
+}
+
+impl Default for Machines {
+    fn default() -> Machines {
+        Machines::new()
     }
 }
 
+/// Shell style wildcard matching, supporting *, ? and character classes like [abc] or [a-z]
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None; // Position after the last *, and the text it resumes at
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some('[') => {
+                if let Some((matched, next)) = match_class(&pattern, p, text[t]) {
+                    if matched {
+                        p = next;
+                        t += 1;
+                        continue;
+                    }
+                } else if text[t] == '[' {
+                    // An unterminated class is a literal bracket
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+            }
+            Some(c) if *c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((after_star, resume)) => {
+                p = after_star;
+                t = resume + 1;
+                backtrack = Some((after_star, resume + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches a character against the class starting at pattern[start], which is a '['.
+/// Returns whether it matched and where the pattern continues, or None if the class is unterminated.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while let Some(&class_char) = pattern.get(i) {
+        if class_char == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|end| *end != ']') {
+            matched |= class_char <= c && c <= pattern[i + 2];
+            i += 3;
+        } else {
+            matched |= class_char == c;
+            i += 1;
+        }
+        first = false;
+    }
+    None
+}
+
 // I would like to have some kind of iterator comparison here (for the newline), for now this will do...
 impl std::fmt::Display for Machines {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -117,22 +572,22 @@ mod tests {
     #[test]
     fn init_machines_and_add() {
         let mut m = Machines::new();
-        m.add("Demo_Machine", "FF:FF:FF:FF:FF:FF", None);
+        m.add("Demo_Machine", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None);
         assert_eq!(1, m.list.len());
-        m.add("Demo_Machine2", "FF:FF:FF:FF:FF:FF", None);
+        m.add("Demo_Machine2", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None);
         assert_eq!(2, m.list.len());
     }
 
     #[test]
     fn write_and_load_from_file() {
         let mut m = Machines::new();
-        m.add("File_Demo_Machine", "FF:FF:FF:FF:FF:FF", None);
+        m.add("File_Demo_Machine", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None);
         assert_eq!(1, m.list.len());
         let path = PathBuf::from("./DEMO_MACHINES.json");
         std::fs::File::create(&path).unwrap();
         m.dump(&path).unwrap();
         // println!("{}", path. to_str().unwrap());
-        let m2 = Machines::from_json_file(&path).unwrap();
+        let m2 = Machines::from_file(&path).unwrap();
 
         assert_eq!(1, m2.list.len());
 
@@ -140,4 +595,186 @@ mod tests {
         // TODO: Branch eq check to a panic instead
         std::fs::remove_file(&path).unwrap();
     }
+
+    #[test]
+    fn remove_and_rename() {
+        let mut m = Machines::new();
+        m.add("Demo_Machine", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None);
+        m.add("Demo_Machine2", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None);
+
+        assert!(matches!(m.rename("Demo_Machine", "Demo_Machine2"), Err(WakerError::Config(_))));
+        assert!(matches!(m.rename("Demo_Machine", " "), Err(WakerError::Parse(_))));
+        assert!(matches!(m.rename("Demo_Machine", "wake"), Err(WakerError::Config(_))));
+        m.rename("Demo_Machine", "Renamed_Machine").unwrap();
+        assert!(m.find("Demo_Machine").is_none());
+        assert!(m.find("Renamed_Machine").is_some());
+
+        assert_eq!("Demo_Machine2", m.remove("Demo_Machine2").unwrap().name);
+        assert!(m.remove("Demo_Machine2").is_err());
+        assert_eq!(1, m.list.len());
+    }
+
+    #[test]
+    fn resolve_targets() {
+        let mut m = Machines::new();
+        m.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        m.add("desktop-1", "AA:BB:CC:DD:EE:02".parse().unwrap(), None);
+        m.add("desktop-2", "AA:BB:CC:DD:EE:03".parse().unwrap(), None);
+        let names = |targets: &[&str]| -> Result<Vec<String>, WakerError> {
+            let targets: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+            Ok(m.resolve(&targets)?.into_iter().map(|host| host.name).collect())
+        };
+
+        assert_eq!(vec!["nas"], names(&["nas"]).unwrap());
+        assert_eq!(vec!["desktop-2"], names(&["2"]).unwrap());
+        assert_eq!(vec!["desktop-1", "desktop-2"], names(&["desk*"]).unwrap());
+        assert_eq!(vec!["desktop-1"], names(&["desktop-[!2]"]).unwrap());
+        assert_eq!(vec!["nas", "desktop-1", "desktop-2"], names(&["aa-bb-cc-dd-ee-01", "desktop-?", "nas"]).unwrap());
+        assert_eq!(vec!["00:11:22:33:44:55"], names(&["00:11:22:33:44:55"]).unwrap());
+        assert!(matches!(names(&["3"]), Err(WakerError::Config(_))));
+        assert!(matches!(names(&["laptop*"]), Err(WakerError::Config(_))));
+
+        // A host named after another host's index is ambiguous
+        m.add("0", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        let targets = vec!["0".to_string()];
+        assert!(matches!(m.resolve(&targets), Err(WakerError::Parse(_))));
+        let targets = vec!["AA:BB:CC:DD:EE:01".to_string()];
+        assert!(matches!(m.resolve(&targets), Err(WakerError::Parse(_))));
+    }
+
+    #[test]
+    fn groups_and_tags() {
+        let mut m = Machines::new();
+        m.add("build-1", "AA:BB:CC:DD:EE:01".parse().unwrap(), None).tags = vec!["build".to_string()];
+        m.add("build-2", "AA:BB:CC:DD:EE:02".parse().unwrap(), None).tags = vec!["build".to_string()];
+        m.add("nas", "AA:BB:CC:DD:EE:03".parse().unwrap(), None);
+        m.group_add("rack2", "nas").unwrap();
+        m.group_add("rack2", "build-2").unwrap();
+        m.group_add("rack2", "nas").unwrap();
+        assert!(matches!(m.group_add("rack2", "laptop"), Err(WakerError::Config(_))));
+        assert_eq!(vec!["rack2"], m.groups_of("nas"));
+
+        let selection = Selection {
+            targets: vec!["nas".to_string()],
+            tags: vec!["build".to_string()],
+            groups: vec!["rack2".to_string()],
+        };
+        let names: Vec<String> = m.select(&selection).unwrap().into_iter().map(|host| host.name).collect();
+        assert_eq!(vec!["nas", "build-1", "build-2"], names);
+        assert!(m.tagged("office").is_err());
+        assert!(m.group("rack3").is_err());
+
+        m.rename("nas", "storage").unwrap();
+        assert_eq!(vec!["storage", "build-2"], m.groups["rack2"]);
+        m.remove("build-2").unwrap();
+        m.group_remove("rack2", "storage").unwrap();
+        assert!(m.groups["rack2"].is_empty());
+        assert!(m.group_remove("rack2", "storage").is_err());
+        m.group_delete("rack2").unwrap();
+        assert!(m.group_delete("rack2").is_err());
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*c", "abbbc"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*c", "abcd"));
+        assert!(glob_match("[a-c]?", "bz"));
+        assert!(!glob_match("[^a-c]?", "bz"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("a[", "a["));
+    }
+
+    #[test]
+    fn status_listing() {
+        let mut m = Machines::new();
+        m.add("Demo_Machine", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None);
+        m.add("Demo_Machine2", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None);
+        let listing = m.with_status(&[Status::Up, Status::Unknown]);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("0  up      Demo_Machine"));
+        assert!(lines[1].starts_with("1  unknown Demo_Machine2"));
+    }
+
+    #[test]
+    fn system_layer() {
+        let mut system = Machines::new();
+        system.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        system.add("printer", "AA:BB:CC:DD:EE:02".parse().unwrap(), None);
+        system.group_add("rack", "nas").unwrap();
+        let mut m = Machines::new();
+        m.add("desktop", "AA:BB:CC:DD:EE:03".parse().unwrap(), None);
+        m.add("printer", "AA:BB:CC:DD:EE:04".parse().unwrap(), None);
+        m.merge_system_layer(SystemLayer {
+            path: PathBuf::from("/etc/waker/hosts.json"),
+            hosts: system.list,
+            groups: system.groups,
+        });
+
+        let names: Vec<&str> = m.list.iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(vec!["desktop", "printer", "nas"], names);
+        assert_eq!("AA:BB:CC:DD:EE:04", m.find("printer").unwrap().macs[0].to_string());
+        let listing = m.with_status(&[Status::Up, Status::Up, Status::Down]);
+        assert!(listing.lines().nth(2).unwrap().starts_with("2  down    system  nas"));
+        assert!(matches!(m.remove("nas"), Err(WakerError::Config(_))));
+        assert!(matches!(m.rename("printer", "laser"), Err(WakerError::Config(_))));
+
+        // Changing a system host or its groups copies only the change to the user config
+        m.find_mut("nas").unwrap().port = Some(7);
+        m.group_add("rack", "desktop").unwrap();
+        let user = m.user_layer();
+        let names: Vec<&str> = user.list.iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(vec!["desktop", "printer", "nas"], names);
+        assert_eq!(vec!["desktop"], user.groups["rack"]);
+        m.find_mut("nas").unwrap().port = None;
+        assert_eq!(2, m.user_layer().list.len());
+
+        // Entries of the per-user config stay there, even if the system config has the same
+        let mut system = Machines::new();
+        system.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        system.group_add("rack", "nas").unwrap();
+        let mut m = Machines::new();
+        m.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+        m.group_add("rack", "nas").unwrap();
+        m.merge_system_layer(SystemLayer {
+            path: PathBuf::from("/etc/waker/hosts.json"),
+            hosts: system.list,
+            groups: system.groups,
+        });
+        assert_eq!(Layer::User, m.layer_of(&m.list[0]));
+        let user = m.user_layer();
+        assert_eq!(1, user.list.len());
+        assert_eq!(vec!["nas"], user.groups["rack"]);
+    }
+
+    #[test]
+    fn load_invalid_file() {
+        let path = PathBuf::from("./DEMO_INVALID_MACHINES.json");
+        std::fs::write(&path, r#"{"list":[{"name":"a","macs":["nope"],"ips":[]}]}"#).unwrap();
+        let result = Machines::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(WakerError::Config(_))));
+
+        // A malformed SecureOn password fails the load too, not just the wakes of its host
+        std::fs::write(&path, r#"{"list":[{"name":"a","macs":["01:02:03:04:05:06"],"ips":[],"password":"nope"}]}"#).unwrap();
+        let result = Machines::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(WakerError::Config(_))));
+    }
+
+    #[test]
+    fn wakeall_continues_past_failures() {
+        let mut m = Machines::new();
+        m.add("Broken_Password", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None).password = Some("nope".to_string());
+        m.add("Broken_Ethernet", "FF:FF:FF:FF:FF:FF".parse().unwrap(), None).ethernet = Some("no-such-interface0".to_string());
+        let report = m.wakeall(None);
+        assert_eq!(2, report.results.len());
+        assert_eq!("Broken_Password", report.results[0].0);
+        assert!(matches!(report.results[0].1, Err(WakerError::Parse(_))));
+        assert_eq!("Broken_Ethernet", report.results[1].0);
+        assert!(matches!(report.results[1].1, Err(WakerError::Network(_))));
+        assert!(matches!(report.into_result(), Err(WakerError::Parse(_))));
+    }
 }
@@ -3,8 +3,8 @@
 
 use std::fs::{self, File, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
-use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::net::IpAddr;
 use std::str::FromStr;
 
 // use std::{fs::{File, OpenOptions, metadata}, io::{Read, Write}, path::{Path, PathBuf}};
@@ -12,15 +12,13 @@ use std::str::FromStr;
 //use serde_json::to;
 
 mod cli_args; // Provides a custom function that specifies our command line options
-mod host; // The actual Host struct
 mod input; // Gives us a python-like input function, as well as a simple confirm function
-mod machines; // Struct that holds a vec of Hosts, as well as operations on those
-mod packet; // The actual magic packet struct, with wake methods e.t.c.
-mod sanitizers; // Functions that sanitizes MAC and IP addresses
 
-// use crate::packet::*;
-use crate::machines::*;
-use host::Host;
+// Everything that is not about prompting or parsing arguments lives in the library
+use waker::{backup, commands, config, export, import, journal, migrate, pacing, packet, sanitizers, status};
+use waker::machines::{self, *};
+use waker::wake::{wake_hosts, WakeOptions};
+use waker::{Host, MacAddress, WakerError};
 use input::*;
 
 // waker -a, --all                  // Wake all configured machines
@@ -34,18 +32,29 @@ use input::*;
 // This should later be matched in the main program to execute the corresponding functionality
 /// Root enum for dictating program behaviour
 pub enum RunMode {
-    Wake(WakeMode),
+    Wake(WakeMode, WakeOptions),
     Edit,
-    Add,
-    List,
+    Add(commands::AddArgs),            // Prompts for anything missing
+    Remove(Option<String>),            // Prompts for which host if no name is given
+    Set(commands::SetArgs),            // Opens the editor, of the named host if there is one, without changes
+    Rename(Option<String>, Option<String>), // Old and new name, prompts for missing ones
+    Group(commands::GroupArgs),        // Lists groups when no changes are given
+    Import(import::ImportArgs),        // Merge hosts from another config file
+    Export(export::ExportArgs),        // Render hosts for other tools
+    Config(config::ConfigCommand),     // Operations on the config file itself
+    List(status::ProbeOptions),
     Backup(BackupMode),
+    Restore(String),                   // Backup name, index or path to replace the config with
+    Undo,                              // Revert the last change recorded in the journal
+    Redo,                              // Replay the last undone change
+    History,                           // List the changes recorded in the journal
 }
 
 /// Specifies how and which machines should be wol'ed
 pub enum WakeMode {
     WakeAll,                 // Wake every configured machine
     WakeSome,                // Interactively pick hosts to wake
-    DirectMacs(Vec<String>), // Wake these mac adresses, non-blocking
+    Targets(machines::Selection), // Wake hosts by name, glob, index, MAC, tag or group, without prompting
 }
 
 // /// Specifies how to perform edits
@@ -57,15 +66,19 @@ pub enum WakeMode {
 
 // Describes how to edit a host
 enum HostEditMode {
-    EditName,
     EditIps,
     EditMacs,
+    EditPassword,
+    EditEthernet,
+    EditNetwork,
+    EditTags,
 }
 
 /// Specifies how the program should backup its config file
 pub enum BackupMode {
     ToFile(String), // Write to file
     ToStdout,       // Write to stdout
+    List,           // List the automatic backups
 }
 
 // fn prompt_file_creation(config_path: &PathBuf) -> Result<(), Box<dyn Error>> {
@@ -120,6 +133,21 @@ fn select_option(text: &str, options: &Vec<String>) -> Option<i32> {
     }
 }
 
+/// Parses a MAC address typed in at a prompt
+fn parse_mac_input(mac_str: &str) -> Result<MacAddress, WakerError> {
+    sanitizers::parse_mac(mac_str, sanitizers::Mode::Lenient)
+}
+
+/// Parses an IP address typed in at a prompt
+fn parse_ip_input(ip_str: &str) -> Result<IpAddr, WakerError> {
+    return sanitizers::parse_ip(ip_str, sanitizers::Mode::Lenient);
+}
+
+/// Formats a list of addresses for use with select_option
+fn to_strings<T: ToString>(items: &[T]) -> Vec<String> {
+    items.iter().map(|item| item.to_string()).collect()
+}
+
 // INCOMPLETE
 // Return Result<(), dyn Error> ?
 /// Takes a host reference and a HostEditMode.
@@ -127,14 +155,6 @@ fn select_option(text: &str, options: &Vec<String>) -> Option<i32> {
 fn edit_host(host: &mut Host, editmode: HostEditMode) {
     println!("Works");
     match editmode {
-        HostEditMode::EditName => {
-            let new_name = input("New name: ");
-            if new_name.len() > 0 {
-                host.name = new_name;
-            } else {
-                println!("Name unchanged...");
-            }
-        }
         HostEditMode::EditIps => {
             let select = select_option(
                 "What do you want to do?: ",
@@ -149,26 +169,26 @@ fn edit_host(host: &mut Host, editmode: HostEditMode) {
                     match index {
                         0 => { // Add
                             let newip = input("New IP: ");
-                            match sanitizers::sanitize(&newip, sanitizers::AddrType::IPv4) {
-                                Some(ip) => {
+                            match parse_ip_input(&newip) {
+                                Ok(ip) => {
                                     host.ips.push(ip);
                                 }
-                                None => {
-                                    println!("Could not parse IP");
+                                Err(what) => {
+                                    println!("{}", what);
                                 }
                             }
                         }
                         1 => { // Edit
-                            let select = select_option("Which ip?: ", &host.ips);
+                            let select = select_option("Which ip?: ", &to_strings(&host.ips));
                             match select {
                                 Some(index) => {
                                     let newip = input("New IP: ");
-                                    match sanitizers::sanitize(&newip, sanitizers::AddrType::IPv4) {
-                                        Some(ip) => {
+                                    match parse_ip_input(&newip) {
+                                        Ok(ip) => {
                                             host.ips[index as usize] = ip;
                                         }
-                                        None => {
-                                            println!("Could not parse IP");
+                                        Err(what) => {
+                                            println!("{}", what);
                                         }
                                     }
                                 }
@@ -176,7 +196,7 @@ fn edit_host(host: &mut Host, editmode: HostEditMode) {
                             }
                         }
                         2 => { // Remove
-                            let select = select_option("Which ip?: ", &host.ips);
+                            let select = select_option("Which ip?: ", &to_strings(&host.ips));
                             match select {
                                 Some(index) => {
                                     host.ips.remove(index as usize);
@@ -206,26 +226,26 @@ fn edit_host(host: &mut Host, editmode: HostEditMode) {
                     match index {
                         0 => { // Add
                             let newmac = input("New MAC: ");
-                            match sanitizers::sanitize(&newmac, sanitizers::AddrType::MAC) {
-                                Some(mac_addr) => {
+                            match parse_mac_input(&newmac) {
+                                Ok(mac_addr) => {
                                     host.macs.push(mac_addr);
                                 }
-                                None => {
-                                    println!("Could not parse mac_addr");
+                                Err(what) => {
+                                    println!("{}", what);
                                 }
                             }
                         }
                         1 => { // Edit
-                            let select = select_option("Which MAC?: ", &host.macs);
+                            let select = select_option("Which MAC?: ", &to_strings(&host.macs));
                             match select {
                                 Some(index) => {
                                     let newmac = input("New MAC: ");
-                                    match sanitizers::sanitize(&newmac, sanitizers::AddrType::MAC) {
-                                        Some(mac_addr) => {
+                                    match parse_mac_input(&newmac) {
+                                        Ok(mac_addr) => {
                                             host.macs[index as usize] = mac_addr;
                                         }
-                                        None => {
-                                            println!("Could not parse MAC");
+                                        Err(what) => {
+                                            println!("{}", what);
                                         }
                                     }
                                 }
@@ -233,7 +253,7 @@ fn edit_host(host: &mut Host, editmode: HostEditMode) {
                             }
                         }
                         2 => { // Remove
-                            let select = select_option("Which MAC?: ", &host.macs);
+                            let select = select_option("Which MAC?: ", &to_strings(&host.macs));
                             match select {
                                 Some(index) => {
                                     host.macs.remove(index as usize);
@@ -249,6 +269,125 @@ fn edit_host(host: &mut Host, editmode: HostEditMode) {
                 }
             }
         }
+        HostEditMode::EditPassword => {
+            if let Some(password) = &host.password {
+                println!("Current password: {}", password);
+            }
+            let new_password = input("New SecureOn password (Blank to remove): ");
+            if new_password.is_empty() {
+                host.password = None;
+                println!("Password removed...");
+            } else {
+                match packet::MagicPacket::parse_password(&new_password) {
+                    Ok(_) => host.password = Some(new_password),
+                    Err(what) => println!("{}", what),
+                }
+            }
+        }
+        HostEditMode::EditEthernet => {
+            if let Some(interface) = &host.ethernet {
+                println!("Currently sending raw Ethernet frames on: {}", interface);
+            }
+            let new_interface = input("Interface for raw Ethernet wakes (Blank for UDP): ");
+            if new_interface.is_empty() {
+                host.ethernet = None;
+                println!("Using UDP...");
+            } else {
+                host.ethernet = Some(new_interface);
+            }
+        }
+        HostEditMode::EditNetwork => {
+            match host.destination6() {
+                Some(destination) => println!("Currently sending to {}", destination),
+                None => println!("Currently sending to {} from {}", host.destination(), host.source_addr()),
+            }
+            println!("Leave a field blank to unset it.");
+            let broadcast = input("Broadcast address (i.e. 192.168.1.255): ");
+            match broadcast.parse() {
+                Ok(broadcast) => host.broadcast = Some(broadcast),
+                Err(_) if broadcast.is_empty() => host.broadcast = None,
+                Err(_) => println!("Could not parse broadcast address"),
+            }
+            let ipv6 = input("IPv6 address to send to instead (i.e. ff02::1, blank for IPv4): ");
+            match sanitizers::parse_ipv6(&ipv6, sanitizers::Mode::Lenient) {
+                Ok(ipv6) => host.ipv6 = Some(ipv6),
+                Err(_) if ipv6.trim().is_empty() => host.ipv6 = None,
+                Err(what) => println!("{}", what),
+            }
+            let prefix = input("Subnet prefix length, for directed broadcasts (i.e. 24): ");
+            match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= 32 => host.prefix = Some(prefix),
+                _ if prefix.is_empty() => host.prefix = None,
+                _ => println!("Could not parse prefix length"),
+            }
+            let port = input("UDP port (i.e. 7 or 9): ");
+            match port.parse() {
+                Ok(port) => host.port = Some(port),
+                Err(_) if port.is_empty() => host.port = None,
+                Err(_) => println!("Could not parse port"),
+            }
+            let interface = input("Network interface to send from (i.e. eth0): ");
+            host.interface = if interface.is_empty() { None } else { Some(interface) };
+            let source = input("Source address to send from: ");
+            match source.parse() {
+                Ok(source) => host.source = Some(source),
+                Err(_) if source.is_empty() => host.source = None,
+                Err(_) => println!("Could not parse source address"),
+            }
+            let repeat = input("Times to send every packet (i.e. 3): ");
+            match pacing::parse_repeat(&repeat) {
+                Ok(repeat) => host.repeat = Some(repeat),
+                Err(_) if repeat.is_empty() => host.repeat = None,
+                Err(what) => println!("{}", what),
+            }
+            let interval = input("Milliseconds between repeats: ");
+            match interval.parse() {
+                Ok(interval) => host.interval = Some(interval),
+                Err(_) if interval.is_empty() => host.interval = None,
+                Err(_) => println!("Could not parse interval"),
+            }
+            let burst = input("Also send to every broadcast and unicast address of the host? (y/n): ");
+            host.burst = match burst.to_lowercase().as_str() {
+                "" => None,
+                "y" | "yes" => Some(true),
+                _ => Some(false),
+            };
+            match host.destination6() {
+                Some(destination) => println!("Now sending to {}", destination),
+                None => println!("Now sending to {} from {}", host.destination(), host.source_addr()),
+            }
+        }
+        HostEditMode::EditTags => {
+            println!("Current tags: {}", host.tags.join(", "));
+            let tags_str = input("New tags (Comma separated, blank to remove all): ");
+            let tags: Result<Vec<String>, WakerError> = tags_str
+                .split(',')
+                .filter(|tag| !tag.trim().is_empty())
+                .map(commands::parse_tag)
+                .collect();
+            match tags {
+                Ok(tags) => host.tags = tags,
+                Err(what) => println!("{}", what),
+            }
+        }
+    }
+}
+
+/// Replaces the groups a host belongs to with the ones the user enters
+fn edit_groups(machines: &mut Machines, name: &str) {
+    println!("Current groups: {}", machines.groups_of(name).join(", "));
+    let groups_str = input("New groups (Comma separated, blank to leave all): ");
+    let wanted: Vec<&str> = groups_str.split(',').map(|group| group.trim()).filter(|group| !group.is_empty()).collect();
+    let current: Vec<String> = machines.groups_of(name).iter().map(|group| group.to_string()).collect();
+    for group in current.iter().filter(|group| !wanted.contains(&group.as_str())) {
+        if let Err(what) = machines.group_remove(group, name) {
+            println!("{}", what);
+        }
+    }
+    for group in wanted {
+        if let Err(what) = machines.group_add(group, name) {
+            println!("{}", what);
+        }
     }
 }
 
@@ -262,24 +401,8 @@ fn edit_machines(machines: &mut Machines) {
             0 => break,
             1 => {
                 println!("Selected: {}", machines.list[index_vec[0] as usize].name);
-                let index = index_vec[0] as usize;
-                let host = &mut machines.list[index];
-                println!("1. Name\n2. IP addresses\n3. Mac addresses\n4. Delete");
-                let choice = parse_integers(&input("What would you like to edit? (Integer): "));
-                match choice.len() {
-                    0 => break,
-                    1 => match choice[0] {
-                        1 => edit_host(host, HostEditMode::EditName),
-                        2 => edit_host(host, HostEditMode::EditIps),
-                        3 => edit_host(host, HostEditMode::EditMacs),
-                        4 => {
-                            if confirm(&format!("Really delete host \"{}\"", machines.list[index].name)) {
-                                machines.list.remove(index);
-                            }
-                        }
-                        _ => break,
-                    },
-                    _ => break,
+                if !edit_machine(machines, index_vec[0] as usize) {
+                    break;
                 }
             }
             _ => break,
@@ -288,6 +411,43 @@ fn edit_machines(machines: &mut Machines) {
     }
 }
 
+/// Asks what to edit about the host at index, and edits it.
+/// Returns false if nothing was chosen, so the caller stops asking.
+fn edit_machine(machines: &mut Machines, index: usize) -> bool {
+    let name = machines.list[index].name.clone();
+    let read_only = machines.check_removable(&name).err();
+    let host = &mut machines.list[index];
+    println!("1. Name\n2. IP addresses\n3. Mac addresses\n4. SecureOn password\n5. Ethernet interface\n6. Broadcast, port, interface and repeats\n7. Tags\n8. Groups\n9. Delete");
+    let choice = parse_integers(&input("What would you like to edit? (Integer): "));
+    match choice.len() {
+        1 => match choice[0] {
+            1 | 9 if read_only.is_some() => eprintln!("{}", read_only.unwrap()),
+            // Through Machines::rename, which refuses names already taken and renames group members
+            1 => {
+                if let Err(what) = rename_machine(machines, Some(name), None) {
+                    eprintln!("{}", what);
+                }
+            }
+            2 => edit_host(host, HostEditMode::EditIps),
+            3 => edit_host(host, HostEditMode::EditMacs),
+            4 => edit_host(host, HostEditMode::EditPassword),
+            5 => edit_host(host, HostEditMode::EditEthernet),
+            6 => edit_host(host, HostEditMode::EditNetwork),
+            7 => edit_host(host, HostEditMode::EditTags),
+            8 => edit_groups(machines, &name),
+            9 => {
+                if confirm(&format!("Really delete host \"{}\"", name)) {
+                    machines.rename_members(&name, None);
+                    machines.list.remove(index);
+                }
+            }
+            _ => return false,
+        },
+        _ => return false,
+    }
+    return true;
+}
+
 // Needs reworking. It works as intended but can be written significantly more elegant and
 // efficient.
 // TODO: In place searching and parsing
@@ -319,21 +479,214 @@ fn parse_integers(int_str: &String) -> Vec<i32> {
     return return_vector;
 }
 
+/// Adds a host, prompting for anything that was not given on the command line.
+/// Entering a blank name or MAC address aborts without adding anything.
+fn add_machine(machines: &mut Machines, mut args: commands::AddArgs) -> Result<(), WakerError> {
+    if args.is_complete() {
+        return commands::add(machines, &args);
+    }
+    println!("Add new machine:");
+    while args.name.is_none() {
+        let name = input("What would you like to call your host?:\n");
+        if name.is_empty() {
+            return Ok(());
+        }
+        if confirm(&format!("Name: {}, is this correct?", &name)) {
+            args.name = Some(name);
+        }
+    }
+    while args.macs.is_empty() {
+        let mac_str = input("What MAC address is assigned to your host?:\n");
+        if mac_str.is_empty() {
+            return Ok(());
+        }
+        match parse_mac_input(&mac_str) {
+            Ok(mac) => {
+                if confirm(&format!("MAC: {}, is this correct?", mac)) {
+                    args.macs.push(mac.to_string());
+                }
+            }
+            Err(what) => println!("{}", what),
+        }
+    }
+    while args.ips.is_empty() {
+        let ip_str = input("What IP address is assigned to your host?: (Blank for none)\n");
+        if ip_str.is_empty() {
+            break;
+        }
+        match parse_ip_input(&ip_str) {
+            Ok(ip) => {
+                if confirm(&format!("IP: {}, is this correct?", ip)) {
+                    args.ips.push(ip.to_string());
+                }
+            }
+            Err(what) => println!("{}", what),
+        }
+    }
+    while args.password.is_none() {
+        let password_str = input("SecureOn password for your host?: (Blank for none)\n");
+        if password_str.is_empty() {
+            break;
+        }
+        if let Err(what) = packet::MagicPacket::parse_password(&password_str) {
+            println!("{}", what);
+            continue;
+        }
+        if confirm(&format!("Password: {}, is this correct?", &password_str)) {
+            args.password = Some(password_str);
+        }
+    }
+    return commands::add(machines, &args);
+}
+
+/// Lets the user pick a single host by index, returning its name
+fn pick_host(machines: &Machines, message: &str) -> Option<String> {
+    println!("{}", machines);
+    let index_vec = which_indexes(message, machines);
+    let host = index_vec.first().and_then(|index| machines.list.get(*index as usize));
+    return host.map(|host| host.name.clone());
+}
+
+/// Removes the named host, or asks which one to remove if no name is given
+fn remove_machine(machines: &mut Machines, name: Option<String>) -> Result<(), WakerError> {
+    let name = match name {
+        Some(name) => name,
+        None => match pick_host(machines, "Which host do you wish to remove? (Integer): ") {
+            Some(name) if confirm(&format!("Really delete host \"{}\"", name)) => name,
+            _ => return Ok(()),
+        },
+    };
+    machines.remove(&name)?;
+    println!("Removed {}", name);
+    return Ok(());
+}
+
+/// Renames a host, prompting for whichever names are missing
+fn rename_machine(machines: &mut Machines, old_name: Option<String>, new_name: Option<String>) -> Result<(), WakerError> {
+    let old_name = match old_name {
+        Some(old_name) => old_name,
+        None => match pick_host(machines, "Which host do you wish to rename? (Integer): ") {
+            Some(name) => name,
+            None => return Ok(()),
+        },
+    };
+    let new_name = match new_name {
+        Some(new_name) => new_name,
+        None => input("New name: "),
+    };
+    if new_name.is_empty() {
+        println!("Name unchanged...");
+        return Ok(());
+    }
+    machines.rename(&old_name, &new_name)?;
+    println!("Renamed {} to {}", old_name, new_name.trim());
+    return Ok(());
+}
+
+/// Imports hosts from another config file. Without a conflict policy the user picks which hosts
+/// to import and whether conflicting ones should replace the configured hosts.
+fn import_machines(machines: &mut Machines, args: import::ImportArgs) -> Result<(), WakerError> {
+    let path = match (&args.path, args.format.default_path()) {
+        (Some(path), _) => path.clone(),
+        (None, Some(default_path)) => PathBuf::from(default_path),
+        (None, None) => return Err(WakerError::Parse("Which file should be imported?".to_string())),
+    };
+    let incoming = import::load_as(args.format, &path)?;
+    if incoming.list.is_empty() {
+        println!("No hosts to import...");
+        return Ok(());
+    }
+    println!("{}", import::listing(machines, &incoming));
+
+    let (selected, policy): (Vec<usize>, import::ConflictPolicy) = match args.policy {
+        Some(policy) => ((0..incoming.list.len()).collect(), policy),
+        None => {
+            let mut selected = Vec::new();
+            for index in which_indexes("Select which hosts to import (Comma separated integers): ", &incoming) {
+                match index >= 0 && (index as usize) < incoming.list.len() {
+                    true => selected.push(index as usize),
+                    false => println!("No host with index {}", index),
+                }
+            }
+            let conflicts = selected
+                .iter()
+                .filter(|index| import::classify(machines, &incoming.list[**index]) == import::Kind::Conflict)
+                .count();
+            let policy = match conflicts > 0
+                && confirm(&format!("Overwrite {} conflicting host(s)? Otherwise they are skipped.", conflicts))
+            {
+                true => import::ConflictPolicy::Overwrite,
+                false => import::ConflictPolicy::Skip,
+            };
+            (selected, policy)
+        }
+    };
+
+    let summary = import::merge(machines, &incoming, &selected, policy)?;
+    println!("{}", summary);
+    return Ok(());
+}
+
 fn which_indexes<S: AsRef<str>>(message: S, _machines: &Machines) -> Vec<i32> {
     let indexes = input(message.as_ref());
     let integers = parse_integers(&indexes);
     return integers;
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let config_path = match cfg!(debug_assertions) {
+/// Prints the automatic backups of the config, oldest first, with the number of hosts in each
+fn list_backups(config_path: &Path) -> Result<(), WakerError> {
+    let backups = backup::list(config_path)?;
+    if backups.is_empty() {
+        println!("No backups in {}", backup::backups_dir(config_path).display());
+    }
+    for (index, path) in backups.iter().enumerate() {
+        let hosts = match Machines::from_file(&path.to_path_buf()) {
+            Ok(machines) => format!("{} hosts", machines.list.len()),
+            Err(_) => "unreadable".to_string(),
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        println!("{:<3}{:<40}{}", index, name, hosts);
+    }
+    return Ok(());
+}
+
+/// Prints the outcome of a wake for every host, returning the first error encountered
+fn report_wakes(report: WakeReport) -> Result<(), WakerError> {
+    for (name, result) in &report.results {
+        match result {
+            Ok(Some(waited)) => println!("{} is up after {:.1}s", name, waited.as_secs_f32()),
+            Ok(None) => println!("Woke {}", name),
+            Err(what) => eprintln!("Could not wake {}: {}", name, what),
+        }
+    }
+    return report.into_result();
+}
+
+fn main() {
+    if let Err(what) = run() {
+        eprintln!("{}", what);
+        std::process::exit(what.exit_code());
+    }
+}
+
+fn run() -> Result<(), WakerError> {
+    // Figure out how the program should behave
+    let matches = cli_args::get_cli_matches();
+    let run_mode = cli_args::get_runmode(&matches);
+
+    // --config wins over WAKER_CONFIG, which wins over the default location
+    let config_override = config::config_override(cli_args::get_config_file(&matches));
+    let config_path = match config_override.clone() {
+        Some(config_path) => config_path,
+
         // If this is a debug build, the the path becomes ./waker.json, relative to project root
-        true => PathBuf::new().join("waker.json"),
+        None if cfg!(debug_assertions) => config::find_config(&PathBuf::new()),
 
-        // If this is a release build, this is essentially ~/.config/waker.json stored in a pathbuf object
-        false => dirs::config_dir()
-            .expect("Could not find config directory...")
-            .join("waker.json"),
+        // If this is a release build, this is essentially ~/.config/waker.json stored in a pathbuf object.
+        // waker.toml or waker.yaml is used instead if that is what exists.
+        None => config::find_config(
+            &dirs::config_dir().ok_or_else(|| WakerError::Config("Could not find config directory...".to_string()))?,
+        ),
     };
 
     // If file does not exist -> Ask to create it -> dump skeleton json into it
@@ -348,97 +701,196 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
+    // Held until the end of run(), so concurrent wakers can not interleave their edits
+    let _lock = match config::ConfigLock::try_acquire(&config_path)? {
+        Some(lock) => lock,
+        None => {
+            eprintln!("Waiting for another waker to release {}...", config_path.display());
+            config::ConfigLock::acquire(&config_path)?
+        }
+    };
+
+    // These work on the file alone, so they also help when the config no longer loads
+    match &run_mode {
+        RunMode::Backup(BackupMode::List) => {
+            list_backups(&config_path)?;
+            return Ok(());
+        }
+        RunMode::Restore(source) => {
+            let source = backup::find(&config_path, source)?;
+            let (restored, saved) = backup::restore(&config_path, &source, backup::keep_count())?;
+            println!(
+                "Restored {} hosts from {}, the previous config was saved to {}",
+                restored.list.len(),
+                source.display(),
+                saved.display()
+            );
+            return Ok(());
+        }
+        _ => {}
+    }
+
     // TODO: More sophisticated error checking and logging
-    let mut machines = Machines::from_json_file(&config_path)?;
-    
-    // Figure out how the program should behave
-    let run_mode = cli_args::get_runmode();
+    let mut machines = Machines::from_file(&config_path)?;
+    if let Some(system) = config::load_system_layer(Path::new(config::SYSTEM_CONFIG))? {
+        machines.merge_system_layer(system);
+    }
+    let loaded = serde_json::to_value(&machines)?;
+    if let Some(version) = machines.migrated_from {
+        let backup_path = migrate::backup_original(&config_path, version)?;
+        machines.dump(&config_path)?;
+        println!(
+            "Upgraded the config file from version {} to {}, the original was saved to {}",
+            version,
+            migrate::CURRENT_VERSION,
+            backup_path.display()
+        );
+    }
+
+    // Failures while waking are reported once the config has been written back
+    let mut outcome = Ok(());
+
+    // Changes are journaled so they can be undone, except for undoing and redoing themselves
+    let record = !matches!(run_mode, RunMode::Undo | RunMode::Redo);
+    let loaded_list = machines.list.clone();
+    let loaded_groups = machines.groups.clone();
+    let mut replayed: Option<journal::Journal> = None;
 
     match run_mode {
-        RunMode::List => {
-            println!("{}", machines);
+        RunMode::List(probe_options) => {
+            let statuses = status::probe_all(&machines.list, &probe_options);
+            println!("{}", machines.with_status(&statuses));
+            if !machines.groups.is_empty() {
+                println!("\nGroups:\n{}", machines.group_listing());
+            }
         }
-        RunMode::Wake(wake_mode) => {
+        RunMode::Wake(wake_mode, mut wake_options) => {
+            wake_options.default_pacing = machines.pacing;
             match wake_mode {
                 WakeMode::WakeAll => {
                     if confirm("You are about to wake all configured machines.\nContinue?") {
-                        machines.wakeall();
-                        for host in &machines.list {
-                            println!("Woke {}", host.name)
-                        }
+                        outcome = report_wakes(wake_hosts(&machines.list.iter().collect::<Vec<&Host>>(), &wake_options));
                     }
                 }
                 WakeMode::WakeSome => {
                     if ! machines.list.is_empty() {
-                        println!("{}", machines);
+                        let statuses = status::probe_all(&machines.list, &wake_options.probe);
+                        println!("{}", machines.with_status(&statuses));
                         let indexes = which_indexes(
                             "Select which hosts to wake up (Comma separated integers): ",
                             &machines,
                         );
+                        let mut hosts = Vec::new();
                         for index in indexes {
-                            // TODO: Bounds checking
-                            let host = &machines.list[index as usize];
-                            host.wake();
-                            println!("Woke {}", host.name)
+                            match machines.list.get(index as usize) {
+                                Some(host) => hosts.push(host),
+                                None => println!("No host with index {}", index),
+                            }
                         }
+                        outcome = report_wakes(wake_hosts(&hosts, &wake_options));
                     }
                     else {
                         println!("No machines configured yet... Try \"waker --help\" for information about usage");
                     }
                 }
-                _ => println!("undefined"),
+                WakeMode::Targets(targets) => {
+                    let hosts = machines.select(&targets)?;
+                    outcome = report_wakes(wake_hosts(&hosts.iter().collect::<Vec<&Host>>(), &wake_options));
+                }
             }
         }
         RunMode::Edit => {
             edit_machines(&mut machines);
         }
-        RunMode::Add => {
-            println!("Add new machine:");
-            let mut add_machine: bool = true;
-            let mut name: String = String::from("");
-            let mut mac_addr: String = String::from("");
-            let mut ip_addr: Option<String> = None;
-            while add_machine {
-                name = input("What would you like to call your host?:\n");
-                if name.is_empty() {
-                    add_machine = false;
-                    break;
-                }
-                if confirm(&format!("Name: {}, is this correct?", &name)) {
-                    break;
-                }
+        RunMode::Add(add_args) => {
+            add_machine(&mut machines, add_args)?;
+        }
+        RunMode::Remove(name) => {
+            remove_machine(&mut machines, name)?;
+        }
+        RunMode::Set(set_args) => {
+            match &set_args.name {
+                Some(_) if set_args.has_changes() => commands::set(&mut machines, &set_args)?,
+                // Without changes, open the editor of the named host rather than the host list
+                Some(name) => match machines.list.iter().position(|host| &host.name == name) {
+                    Some(index) => {
+                        edit_machine(&mut machines, index);
+                    }
+                    None => return Err(WakerError::Config(format!("No host named \"{}\"", name))),
+                },
+                None => edit_machines(&mut machines),
             }
-            while add_machine {
-                mac_addr = input("What MAC address is assigned to your host?:\n");
-                if mac_addr.is_empty() {
-                    add_machine = false;
-                    break;
-                }
-                if confirm(&format!("MAC: {}, is this correct?", &mac_addr)) {
-                    break;
+        }
+        RunMode::Group(group_args) => {
+            if group_args.has_changes() {
+                commands::group(&mut machines, &group_args)?;
+            } else {
+                match &group_args.name {
+                    Some(name) => {
+                        for host in machines.group(name)? {
+                            println!("{}", host);
+                        }
+                    }
+                    None => println!("{}", machines.group_listing()),
                 }
             }
-            while add_machine {
-                let ip_str = input("What IP address is assigned to your host?: (Blank for none)\n");
-                if ip_str.is_empty() {
-                    ip_addr = None;
-                    break;
-                }
-                if confirm(&format!("IP: {}, is this correct?", &ip_str)) {
-                    ip_addr = Some(ip_str);
-                    break;
-                }
+        }
+        RunMode::Import(import_args) => {
+            import_machines(&mut machines, import_args)?;
+        }
+        RunMode::Export(export_args) => {
+            let rendered = export::render(&machines, export_args.format);
+            match &export_args.output {
+                Some(path) => fs::write(path, rendered)?,
+                None => print!("{}", rendered),
             }
-            if add_machine {
-                machines.add(&name, &mac_addr, ip_addr);
+        }
+        RunMode::Config(config::ConfigCommand::Convert(format)) => {
+            backup::create(&config_path, backup::keep_count())?;
+            // An explicitly named file is kept, removing it would leave the override dangling
+            let new_path = config::convert(&config_path, &machines, format, config_override.is_none())?;
+            println!("Converted {} to {}", config_path.display(), new_path.display());
+            if config_override.is_some() {
+                println!(
+                    "{} was kept, point --config or {} at {} to use the new file",
+                    config_path.display(),
+                    config::CONFIG_ENV,
+                    new_path.display()
+                );
             }
+            // Nothing changed, so there is nothing to write back
+            return Ok(());
         }
+        RunMode::Rename(old_name, new_name) => {
+            rename_machine(&mut machines, old_name, new_name)?;
+        }
+        RunMode::Undo => {
+            let mut journal = journal::Journal::load(&config_path)?;
+            let entry = journal.undo(&mut machines)?;
+            println!("Undid \"waker {}\": {}", entry.command, entry.describe());
+            replayed = Some(journal);
+        }
+        RunMode::Redo => {
+            let mut journal = journal::Journal::load(&config_path)?;
+            let entry = journal.redo(&mut machines)?;
+            println!("Redid \"waker {}\": {}", entry.command, entry.describe());
+            replayed = Some(journal);
+        }
+        RunMode::History => {
+            let journal = journal::Journal::load(&config_path)?;
+            match journal.entries.is_empty() {
+                true => println!("No changes recorded yet"),
+                false => println!("{}", journal.listing()),
+            }
+        }
+        // Handled before the config was loaded
+        RunMode::Restore(_) | RunMode::Backup(BackupMode::List) => {}
         // Might need some polish in regards to guards and error handling.
         // Perhaps there is a cleaner way to do the writing...
         // This seems to work fine for now
         RunMode::Backup(backup_mode) => {
             // Read entire file unbuffered into memory.
-            let content = fs::read_to_string(&config_path).unwrap();
+            let content = fs::read_to_string(&config_path)?;
             match backup_mode {
                 // Another valid, and maybe more concise way of doing this is to just do a plain
                 // copy of the config file into the *valid* file_string destination.
@@ -449,13 +901,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                     // TODO: Further guards
                     if !backup_file.exists() && !backup_file.is_dir() {
                         println!("Executing file backup");
-                        let mut backup_file_handle = OpenOptions::new().create(true).write(true).open(backup_file).unwrap();
-                        backup_file_handle.write_all(content.as_bytes()).unwrap();
+                        let mut backup_file_handle = OpenOptions::new().create(true).write(true).truncate(true).open(backup_file)?;
+                        backup_file_handle.write_all(content.as_bytes())?;
                     }
                     else if backup_file.exists() && backup_file.is_file() {
                         if confirm(&format!("The file \"{}\" already exists...\nOverwrite?", &file_string)) {
-                            let mut backup_file_handle = OpenOptions::new().write(true).open(backup_file).unwrap();
-                            backup_file_handle.write_all(content.as_bytes()).unwrap();
+                            let mut backup_file_handle = OpenOptions::new().write(true).truncate(true).open(backup_file)?;
+                            backup_file_handle.write_all(content.as_bytes())?;
                         }
                     }
                     else {
@@ -471,12 +923,32 @@ fn main() -> Result<(), Box<dyn Error>> {
                         println!("{}", line);
                     }
                 },
+                BackupMode::List => {},
             }
         }
     }
 
-    machines.dump(&config_path)?;
-    return Ok(());
+    // Only rewrite the file if something changed, a plain wake leaves it untouched
+    if serde_json::to_value(&machines)? != loaded {
+        backup::create(&config_path, backup::keep_count())?;
+        machines.dump(&config_path)?;
+        if record {
+            let mut journal = journal::Journal::load(&config_path)?;
+            let groups_changed = machines.groups != loaded_groups;
+            journal.record(journal::Entry {
+                time: backup::timestamp(std::time::SystemTime::now()),
+                command: std::env::args().skip(1).collect::<Vec<String>>().join(" "),
+                changes: journal::diff(&loaded_list, &machines.list),
+                groups: if groups_changed { Some((loaded_groups, machines.groups.clone())) } else { None },
+            });
+            journal.save(&config_path)?;
+        }
+    }
+    // Only once the config itself is written, so the two can not disagree after a failure
+    if let Some(journal) = replayed {
+        journal.save(&config_path)?;
+    }
+    return outcome;
 }
 
 #[cfg(test)]
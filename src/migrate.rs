@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::error::WakerError;
+use crate::sanitizers::{self, Mode};
+
+// Config layouts, the "version" key says which one a file uses:
+//   0  No version key. Hosts hold MAC and IP addresses as whatever was typed at the prompt,
+//      optional fields (password, ethernet, broadcast...) may or may not be present.
+//   1  Addresses are stored in canonical form (XX:XX:XX:XX:XX:XX, std IP notation),
+//      hosts may carry tags and the config may hold groups.
+
+/// Layout written by this version of waker
+pub const CURRENT_VERSION: u64 = 1;
+
+/// Upgrades a config, as parsed from any of the supported formats, to the current layout.
+/// Returns the upgraded config and the version it was upgraded from.
+pub fn migrate(mut config: Value) -> Result<(Value, u64), WakerError> {
+    let version = match config.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| WakerError::Config(format!("Invalid config version: {}", version)))?,
+    };
+    if version > CURRENT_VERSION {
+        return Err(WakerError::Config(format!(
+            "The config file is version {}, this waker only understands up to version {}",
+            version, CURRENT_VERSION
+        )));
+    }
+
+    if version < 1 {
+        v0_to_v1(&mut config)?;
+    }
+
+    if let Some(config) = config.as_object_mut() {
+        config.insert("version".to_string(), Value::from(CURRENT_VERSION));
+    }
+    Ok((config, version))
+}
+
+/// Canonicalizes the free form addresses of version 0. Blank IPs are dropped, anything else that
+/// can not be read is an error, rather than silently losing the address.
+fn v0_to_v1(config: &mut Value) -> Result<(), WakerError> {
+    let hosts = match config.get_mut("list").and_then(|list| list.as_array_mut()) {
+        Some(hosts) => hosts,
+        None => return Ok(()),
+    };
+    for host in hosts {
+        let name = host.get("name").and_then(|name| name.as_str()).unwrap_or_default().to_string();
+        let invalid = |what: WakerError| WakerError::Config(format!("Host \"{}\": {}", name, what));
+
+        if let Some(macs) = host.get_mut("macs").and_then(|macs| macs.as_array_mut()) {
+            for mac in macs.iter_mut() {
+                let mac_str = mac.as_str().unwrap_or_default();
+                *mac = Value::from(sanitizers::parse_mac(mac_str, Mode::Lenient).map_err(invalid)?.to_string());
+            }
+        }
+        if let Some(ips) = host.get_mut("ips").and_then(|ips| ips.as_array_mut()) {
+            ips.retain(|ip| !ip.as_str().unwrap_or_default().trim().is_empty());
+            for ip in ips.iter_mut() {
+                let ip_str = ip.as_str().unwrap_or_default();
+                *ip = Value::from(sanitizers::parse_ip(ip_str, Mode::Lenient).map_err(invalid)?.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies a config file that is about to be upgraded to "<file>.v<version>.bak", numbering the
+/// copy if an older backup is already there. Returns where the copy went.
+pub fn backup_original(config_path: &Path, version: u64) -> Result<PathBuf, WakerError> {
+    let file_name = config_path.file_name().and_then(|name| name.to_str()).unwrap_or("waker.json");
+    let mut backup_path = config_path.with_file_name(format!("{}.v{}.bak", file_name, version));
+    let mut copy = 1;
+    while backup_path.exists() {
+        backup_path = config_path.with_file_name(format!("{}.v{}.{}.bak", file_name, version, copy));
+        copy += 1;
+    }
+    std::fs::copy(config_path, &backup_path)?;
+    Ok(backup_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigFormat;
+    use crate::machines::Machines;
+
+    fn load(format: ConfigFormat, text: &str) -> Result<(Machines, u64), WakerError> {
+        let (config, version) = migrate(format.deserialize_value(text)?)?;
+        Ok((serde_json::from_value(config)?, version))
+    }
+
+    #[test]
+    fn v0_baseline() {
+        let (m, version) = load(ConfigFormat::Json, include_str!("../tests/fixtures/config-v0.json")).unwrap();
+        assert_eq!(0, version);
+        assert_eq!(3, m.list.len());
+        assert_eq!("AA:BB:CC:DD:EE:01", m.list[0].macs[0].to_string());
+        assert_eq!(vec!["192.168.1.10".parse::<std::net::IpAddr>().unwrap()], m.list[0].ips);
+        assert_eq!("0A:0B:0C:0D:0E:0F", m.list[1].macs[0].to_string());
+        assert!(m.list[1].ips.is_empty());
+        assert_eq!("AA:BB:CC:DD:EE:03", m.list[2].macs[1].to_string());
+    }
+
+    #[test]
+    fn v0_with_optional_fields() {
+        let (m, version) = load(ConfigFormat::Json, include_str!("../tests/fixtures/config-v0-options.json")).unwrap();
+        assert_eq!(0, version);
+        assert_eq!(Some("01:02:03:04".to_string()), m.list[0].password);
+        assert_eq!("10.0.0.255:7", m.list[0].destination().to_string());
+        assert_eq!(CURRENT_VERSION, m.version);
+    }
+
+    #[test]
+    fn v1_is_unchanged() {
+        for (format, text) in [
+            (ConfigFormat::Json, include_str!("../tests/fixtures/config-v1.json")),
+            (ConfigFormat::Toml, include_str!("../tests/fixtures/config-v1.toml")),
+            (ConfigFormat::Yaml, include_str!("../tests/fixtures/config-v1.yaml")),
+        ] {
+            let value = format.deserialize_value(text).unwrap();
+            let (migrated, version) = migrate(value.clone()).unwrap();
+            assert_eq!(1, version);
+            assert_eq!(value, migrated);
+            let m: Machines = serde_json::from_value(migrated).unwrap();
+            assert_eq!(vec!["nas"], m.groups["rack2"]);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        let bad_mac = r#"{"list":[{"name":"a","macs":["nope"],"ips":[]}]}"#;
+        let what = load(ConfigFormat::Json, bad_mac).err().unwrap().to_string();
+        assert!(what.contains("Host \"a\""), "{}", what);
+        assert!(matches!(load(ConfigFormat::Json, r#"{"version":99,"list":[]}"#), Err(WakerError::Config(_))));
+        assert!(matches!(load(ConfigFormat::Json, r#"{"version":"one","list":[]}"#), Err(WakerError::Config(_))));
+    }
+
+    #[test]
+    fn backups_are_numbered() {
+        let dir = std::env::temp_dir().join(format!("waker-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("waker.json");
+        std::fs::write(&config_path, "{}").unwrap();
+        let first = backup_original(&config_path, 0).unwrap();
+        let second = backup_original(&config_path, 0).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(dir.join("waker.json.v0.bak"), first);
+        assert_eq!(dir.join("waker.json.v0.1.bak"), second);
+    }
+}
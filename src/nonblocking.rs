@@ -0,0 +1,341 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::error::WakerError;
+use crate::host::Host;
+use crate::machines::{Machines, WakeReport};
+use crate::pacing::{Delivery, Pacing};
+use crate::packet::MagicPacket;
+use crate::status::{self, ProbeOptions, Status, WaitOptions, WaitStep, Waiting};
+use crate::transport::{MockTransport, Route, SystemTransport, Transport};
+use crate::wake::{self, WakeOptions};
+
+// Async equivalents of the blocking wake and probe functions, for programs running on tokio.
+// Hosts are handled concurrently as tasks, at most `limit` at a time. The few calls tokio has no
+// async version of (binding a socket to a device, raw Ethernet frames and ICMP echo) run on
+// tokio's blocking thread pool.
+
+/// Hosts woken or probed at the same time, unless told otherwise
+pub const DEFAULT_CONCURRENCY: usize = 64;
+
+/// Future returned by AsyncTransport::send
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// Future returned by AsyncTransport::pause
+pub type PauseFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Async counterpart of Transport
+pub trait AsyncTransport: Send + Sync {
+    fn send<'a>(&'a self, packet: &'a MagicPacket, route: &'a Route) -> SendFuture<'a>;
+
+    /// Waits between repeated packets, as Transport::pause does
+    fn pause(&self, duration: Duration) -> PauseFuture<'_> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Sends packets through tokio's sockets
+pub struct TokioTransport;
+
+impl AsyncTransport for TokioTransport {
+    fn send<'a>(&'a self, packet: &'a MagicPacket, route: &'a Route) -> SendFuture<'a> {
+        Box::pin(async move {
+            match route {
+                Route::Udp { to, from, device: None } => {
+                    let socket = UdpSocket::bind(from).await?;
+                    if to.is_ipv4() {
+                        socket.set_broadcast(true)?;
+                    }
+                    socket.send_to(&packet.bytes, to).await?;
+                    Ok(())
+                }
+                _ => {
+                    let (packet, route) = (packet.clone(), route.clone());
+                    tokio::task::spawn_blocking(move || SystemTransport.send(&packet, &route))
+                        .await
+                        .map_err(io::Error::other)?
+                }
+            }
+        })
+    }
+}
+
+/// Records packets, as the blocking MockTransport does
+impl AsyncTransport for MockTransport {
+    fn send<'a>(&'a self, packet: &'a MagicPacket, route: &'a Route) -> SendFuture<'a> {
+        Box::pin(std::future::ready(Transport::send(self, packet, route)))
+    }
+
+    fn pause(&self, duration: Duration) -> PauseFuture<'_> {
+        Transport::pause(self, duration);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+/// Async equivalent of Host::wake_with
+pub async fn wake_host(transport: &dyn AsyncTransport, host: &Host, ethernet: Option<&str>) -> Result<(), WakerError> {
+    wake_host_paced(transport, host, ethernet, &host.pacing()).await
+}
+
+/// Async equivalent of Host::wake_paced, with the same schedule
+pub async fn wake_host_paced(
+    transport: &dyn AsyncTransport,
+    host: &Host,
+    ethernet: Option<&str>,
+    pacing: &Pacing,
+) -> Result<(), WakerError> {
+    let plan = host.wake_plan(ethernet, pacing)?;
+    let mut delivery = Delivery::default();
+    for round in 0..plan.rounds() {
+        if let Some(pause) = plan.pause_before(round) {
+            transport.pause(pause).await;
+        }
+        for (packet, route) in plan.sends() {
+            delivery.record(transport.send(packet, route).await);
+        }
+        delivery.end_round(round)?;
+    }
+    Ok(())
+}
+
+/// Async equivalent of Machines::wakeall_with, waking at most `limit` hosts at a time
+pub async fn wakeall(transport: Arc<dyn AsyncTransport>, machines: &Machines, ethernet: Option<&str>, limit: usize) -> WakeReport {
+    let options = WakeOptions {
+        ethernet: ethernet.map(|ethernet| ethernet.to_string()),
+        default_pacing: machines.pacing,
+        ..Default::default()
+    };
+    wake_hosts(transport, &machines.list.iter().collect::<Vec<&Host>>(), &options, limit).await
+}
+
+/// Async equivalent of wake::wake_hosts_with, waking at most `limit` hosts at a time
+pub async fn wake_hosts(transport: Arc<dyn AsyncTransport>, hosts: &[&Host], options: &WakeOptions, limit: usize) -> WakeReport {
+    let semaphore = Arc::new(Semaphore::new(limit.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, host) in hosts.iter().enumerate() {
+        let host = (*host).clone();
+        let (transport, semaphore) = (transport.clone(), semaphore.clone());
+        let (ethernet, probe, wait, exec) = (options.ethernet.clone(), options.probe.clone(), options.wait.clone(), options.exec.clone());
+        let pacing = options.pacing_for(&host);
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let result = match wait {
+                None => wake_host_paced(&*transport, &host, ethernet.as_deref(), &pacing).await.map(|_| None),
+                Some(wait) => match wait_for_host(&*transport, &host, &wait, &probe, ethernet.as_deref(), &pacing).await {
+                    Ok(waited) => run_command(exec, host.clone()).await.map(|_| Some(waited)),
+                    Err(what) => Err(what),
+                },
+            };
+            (index, result)
+        });
+    }
+
+    let mut outcomes: Vec<Option<Result<Option<Duration>, WakerError>>> = hosts.iter().map(|_| None).collect();
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok((index, result)) = joined {
+            outcomes[index] = Some(result);
+        }
+    }
+    let results = hosts
+        .iter()
+        .zip(outcomes)
+        .map(|(host, outcome)| {
+            let result = outcome.unwrap_or_else(|| Err(WakerError::Io(io::Error::other("Wake task panicked"))));
+            (host.name.clone(), result)
+        })
+        .collect();
+    WakeReport { results }
+}
+
+async fn run_command(exec: Option<String>, host: Host) -> Result<(), WakerError> {
+    match exec {
+        Some(command) => tokio::task::spawn_blocking(move || wake::run_command(&command, &host))
+            .await
+            .map_err(|what| WakerError::Io(io::Error::other(what)))?,
+        None => Ok(()),
+    }
+}
+
+/// Async equivalent of status::wait_for_host, waking through the given transport
+pub async fn wait_for_host(
+    transport: &dyn AsyncTransport,
+    host: &Host,
+    wait: &WaitOptions,
+    probe: &ProbeOptions,
+    ethernet: Option<&str>,
+    pacing: &Pacing,
+) -> Result<Duration, WakerError> {
+    let mut waiting = Waiting::start(host, wait, probe)?;
+    loop {
+        if waiting.wake_due() {
+            wake_host_paced(transport, host, ethernet, pacing).await?;
+        }
+        match waiting.after_probe(probe_host(host, probe).await == Status::Up)? {
+            WaitStep::Done(took) => return Ok(took),
+            WaitStep::Sleep(pause) => tokio::time::sleep(pause).await,
+        }
+    }
+}
+
+/// Probes a single IP address as status::probe_all does, ICMP first and then the TCP ports,
+/// which are tried concurrently
+pub async fn probe_ip(ip: IpAddr, options: &ProbeOptions) -> Status {
+    probe_addr(SocketAddr::new(ip, 0), options).await
+}
+
+/// Probes a single address like probe_ip, keeping its IPv6 scope id. The port is ignored.
+pub async fn probe_addr(addr: SocketAddr, options: &ProbeOptions) -> Status {
+    if options.icmp {
+        let timeout = options.timeout;
+        if let Ok(Ok(true)) = tokio::task::spawn_blocking(move || status::icmp_echo(addr, timeout)).await {
+            return Status::Up;
+        }
+    }
+    let mut connects = JoinSet::new();
+    for port in &options.tcp_ports {
+        let (mut target, timeout) = (addr, options.timeout);
+        target.set_port(*port);
+        connects.spawn(async move {
+            match tokio::time::timeout(timeout, TcpStream::connect(target)).await {
+                Ok(Ok(_)) => true,
+                Ok(Err(what)) => what.kind() == io::ErrorKind::ConnectionRefused,
+                Err(_) => false,
+            }
+        });
+    }
+    while let Some(joined) = connects.join_next().await {
+        if let Ok(true) = joined {
+            return Status::Up;
+        }
+    }
+    Status::Down
+}
+
+/// Async equivalent of status::probe_host, probing every IP of the host concurrently
+pub async fn probe_host(host: &Host, options: &ProbeOptions) -> Status {
+    if host.ips.is_empty() {
+        return Status::Unknown;
+    }
+    let mut probes = JoinSet::new();
+    for addr in host.probe_addrs() {
+        let options = options.clone();
+        probes.spawn(async move { probe_addr(addr, &options).await });
+    }
+    let mut status = Status::Down;
+    while let Some(joined) = probes.join_next().await {
+        if let Ok(Status::Up) = joined {
+            status = Status::Up;
+            probes.abort_all();
+        }
+    }
+    status
+}
+
+/// Async equivalent of status::probe_all, probing at most `limit` hosts at a time.
+/// Returns the statuses in the same order as the hosts.
+pub async fn probe_all(hosts: &[Host], options: &ProbeOptions, limit: usize) -> Vec<Status> {
+    let semaphore = Arc::new(Semaphore::new(limit.max(1)));
+    let mut probes = JoinSet::new();
+    for (index, host) in hosts.iter().enumerate() {
+        let (host, options, semaphore) = (host.clone(), options.clone(), semaphore.clone());
+        probes.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            (index, probe_host(&host, &options).await)
+        });
+    }
+    let mut statuses = vec![Status::Unknown; hosts.len()];
+    while let Some(joined) = probes.join_next().await {
+        if let Ok((index, status)) = joined {
+            statuses[index] = status;
+        }
+    }
+    statuses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::demo_machines;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    /// Counts how many sends are in flight at once
+    #[derive(Default)]
+    struct SlowTransport {
+        in_flight: AtomicUsize,
+        most: AtomicUsize,
+    }
+
+    impl AsyncTransport for SlowTransport {
+        fn send<'a>(&'a self, _packet: &'a MagicPacket, _route: &'a Route) -> SendFuture<'a> {
+            Box::pin(async move {
+                let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        }
+    }
+
+    #[test]
+    fn wakeall_reports_in_order() {
+        let machines = demo_machines();
+        let transport = Arc::new(MockTransport::failing(|route| matches!(route, Route::Ethernet { .. })));
+        let report = block_on(wakeall(transport.clone(), &machines, None, 2));
+        let names: Vec<&str> = report.results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(vec!["plain", "directed", "bound", "layer2"], names);
+        let failed: Vec<bool> = report.results.iter().map(|(_, result)| result.is_err()).collect();
+        assert_eq!(vec![false, false, false, true], failed);
+        assert_eq!(3, transport.sent().len());
+
+        let report = block_on(wakeall(transport.clone(), &machines, Some("eth0"), 2));
+        assert!(report.results.iter().all(|(_, result)| matches!(result, Err(WakerError::Network(_)))));
+    }
+
+    #[test]
+    fn repeats_use_the_transport_clock() {
+        let mut machines = demo_machines();
+        machines.pacing = Pacing { repeat: Some(2), interval: Some(40), burst: None };
+        let transport = Arc::new(MockTransport::new());
+        let report = block_on(wakeall(transport.clone(), &machines, None, 1));
+        assert!(report.into_result().is_ok());
+        let times: Vec<u128> = transport.schedule().iter().map(|(time, _)| time.as_millis()).collect();
+        assert_eq!(vec![0, 40, 40, 80, 80, 120, 120, 120, 160, 160], times);
+    }
+
+    #[test]
+    fn concurrency_is_bounded() {
+        let transport = Arc::new(SlowTransport::default());
+        let report = block_on(wakeall(transport.clone(), &demo_machines(), None, 3));
+        assert!(report.into_result().is_ok());
+        assert_eq!(3, transport.most.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn probes() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = ProbeOptions {
+            timeout: Duration::from_millis(200),
+            icmp: false,
+            tcp_ports: vec![listener.local_addr().unwrap().port()],
+        };
+        let mut up = Host::new("up", "AA:BB:CC:DD:EE:01".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        up.ips.push("192.0.2.200".parse().unwrap());
+        let mut unknown = up.clone();
+        unknown.ips.clear();
+        let statuses = block_on(probe_all(&[up, unknown], &options, 1));
+        assert_eq!(vec![Status::Up, Status::Unknown], statuses);
+    }
+}
@@ -0,0 +1,149 @@
+use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::WakerError;
+use crate::packet::MagicPacket;
+use crate::transport::Route;
+
+// Plenty of NICs miss the odd magic packet, so every packet can be sent several times, and to
+// several addresses at once. Settings given on the command line win over those of a host, which
+// win over the defaults of the whole config, which win over the constants below.
+
+/// Times every packet is sent unless configured otherwise
+pub const DEFAULT_REPEAT: u32 = 1;
+
+/// Milliseconds between repeats unless configured otherwise
+pub const DEFAULT_INTERVAL: u64 = 100;
+
+/// How many magic packets go out per wake, how far apart and to where. Unset fields fall back
+/// to the next level, see or().
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct Pacing {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<u32>, // Times every packet is sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>, // Milliseconds between repeats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<bool>, // Send to every broadcast and unicast address of the host, see Host::routes
+}
+
+impl Pacing {
+    /// Whether nothing is set, so that the defaults apply
+    pub fn is_unset(&self) -> bool {
+        *self == Pacing::default()
+    }
+
+    /// Fills in whatever is unset from `fallback`
+    pub fn or(self, fallback: Pacing) -> Pacing {
+        Pacing {
+            repeat: self.repeat.or(fallback.repeat),
+            interval: self.interval.or(fallback.interval),
+            burst: self.burst.or(fallback.burst),
+        }
+    }
+
+    /// Times every packet is sent, at least once
+    pub fn repeat(&self) -> u32 {
+        self.repeat.unwrap_or(DEFAULT_REPEAT).max(1)
+    }
+
+    /// Pause between two sends of the same packet
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval.unwrap_or(DEFAULT_INTERVAL))
+    }
+
+    pub fn burst(&self) -> bool {
+        self.burst.unwrap_or(false)
+    }
+}
+
+/// The sends of one wake, as both the blocking and the async drivers carry it out: rounds of
+/// every packet on every route, back to back, with a pause of the transport between rounds.
+/// See Host::wake_plan.
+pub struct WakePlan {
+    packets: Vec<MagicPacket>,
+    routes: Vec<Route>,
+    pacing: Pacing,
+}
+
+impl WakePlan {
+    pub fn new(packets: Vec<MagicPacket>, routes: Vec<Route>, pacing: Pacing) -> WakePlan {
+        WakePlan { packets, routes, pacing }
+    }
+
+    pub fn rounds(&self) -> u32 {
+        self.pacing.repeat()
+    }
+
+    /// How long to pause before the given round, None for the first one
+    pub fn pause_before(&self, round: u32) -> Option<Duration> {
+        (round > 0).then(|| self.pacing.interval())
+    }
+
+    /// The sends of a single round, every packet on every route
+    pub fn sends(&self) -> impl Iterator<Item = (&MagicPacket, &Route)> {
+        self.packets.iter().flat_map(move |packet| self.routes.iter().map(move |route| (packet, route)))
+    }
+}
+
+/// Collects the outcome of the sends of a WakePlan. A failed send does not stop the rest, the
+/// wake only fails if nothing of the first round got out.
+#[derive(Default)]
+pub struct Delivery {
+    delivered: bool,
+    failure: Option<io::Error>,
+}
+
+impl Delivery {
+    pub fn record(&mut self, sent: io::Result<()>) {
+        match sent {
+            Ok(()) => self.delivered = true,
+            Err(what) => self.failure = self.failure.take().or(Some(what)),
+        }
+    }
+
+    /// Called after every round, fails the wake if the first one delivered nothing
+    pub fn end_round(&mut self, round: u32) -> Result<(), WakerError> {
+        if round == 0 && !self.delivered {
+            if let Some(what) = self.failure.take() {
+                return Err(WakerError::Network(what));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a repeat count given on the command line, which has to be at least one
+pub fn parse_repeat(repeat: &str) -> Result<u32, String> {
+    match repeat.trim().parse::<u32>() {
+        Ok(repeat) if repeat > 0 => Ok(repeat),
+        _ => Err(format!("Invalid repeat count: {}", repeat)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallbacks() {
+        let cli = Pacing { repeat: Some(5), ..Default::default() };
+        let host = Pacing { repeat: Some(2), interval: Some(250), burst: None };
+        let config = Pacing { interval: Some(50), burst: Some(true), ..Default::default() };
+        let pacing = cli.or(host).or(config);
+        assert_eq!(Pacing { repeat: Some(5), interval: Some(250), burst: Some(true) }, pacing);
+
+        let unset = Pacing::default();
+        assert!(unset.is_unset());
+        assert_eq!(DEFAULT_REPEAT, unset.repeat());
+        assert_eq!(Duration::from_millis(DEFAULT_INTERVAL), unset.interval());
+        assert!(!unset.burst());
+        assert_eq!(1, Pacing { repeat: Some(0), ..Default::default() }.repeat());
+
+        assert_eq!(Ok(3), parse_repeat("3"));
+        assert!(parse_repeat("0").is_err());
+        assert!(parse_repeat("-1").is_err());
+    }
+}
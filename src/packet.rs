@@ -1,4 +1,8 @@
-use std::{convert::TryInto, error::Error, net::{Ipv4Addr, ToSocketAddrs, UdpSocket}};
+use std::{convert::TryInto, net::{Ipv4Addr, Ipv6Addr, ToSocketAddrs, UdpSocket}, str::FromStr};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use crate::error::WakerError;
+use crate::ethernet;
 use crate::sanitizers::{self, sanitize};
 
 // The format of a Wake-on-LAN (WOL) magic packet is defined
@@ -8,62 +12,168 @@ use crate::sanitizers::{self, sanitize};
 
 const MAGIC_HEADER: [u8; 6] = [0xFF; 6];
 
+/// Port magic packets are sent to unless configured otherwise (the discard service)
+pub const DEFAULT_PORT: u16 = 9;
+
+/// Returns the directed broadcast address of the subnet containing `ip`, i.e. the address with
+/// all host bits set. A /24 host 192.168.1.10 gives 192.168.1.255.
+pub fn directed_broadcast(ip: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let host_mask = match prefix_len {
+        0 => u32::MAX,
+        1..=31 => u32::MAX >> prefix_len,
+        _ => 0,
+    };
+    Ipv4Addr::from(u32::from(ip) | host_mask)
+}
+
+/// Multicast address of every IPv6 node on the link, the IPv6 counterpart of 255.255.255.255
+pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// Whether an IPv6 address only means something on a single link, so that sending to it needs
+/// an interface as its scope. That is link-local unicast (fe80::/10) and multicast (ff02::/16).
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    first & 0xffc0 == 0xfe80 || first & 0xff0f == 0xff02
+}
+
+/// Index of the named network interface, the scope id of link-local IPv6 addresses on it.
+/// None if there is no such interface.
+#[cfg(unix)]
+pub fn interface_index(interface: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(interface).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn interface_index(_interface: &str) -> Option<u32> {
+    None
+}
+
+// NICs configured with a SecureOn password expect it appended to the payload,
+// either as 4 bytes (106 bytes total) or 6 bytes (108 bytes total).
+
 /// Contains raw bytes for magic packet
+#[derive(Clone)]
 pub struct MagicPacket {
-    pub bytes: [u8; 102],
+    pub bytes: Vec<u8>,
 }
 
 impl MagicPacket {
     /// Create new MagicPacket from a raw 6-byte MAC address
     pub fn new(mac_bytes: &[u8; 6]) -> MagicPacket {
-        let mut magic_bytes: [u8; 102];
-        unsafe {
-            magic_bytes = std::mem::MaybeUninit::uninit().assume_init();
-            let mut src: *const u8 = &MAGIC_HEADER[0];
-            let mut dst: *mut u8 = &mut magic_bytes[0];
-            dst.copy_from_nonoverlapping(src, 6);
-
-            src = &mac_bytes[0];
-            for _ in 0..16 {
-                dst = dst.offset(6);
-                dst.copy_from_nonoverlapping(src, 6);
-            }
+        let mut magic_bytes = Vec::with_capacity(108);
+        magic_bytes.extend_from_slice(&MAGIC_HEADER);
+        for _ in 0..16 {
+            magic_bytes.extend_from_slice(mac_bytes);
         }
-        return MagicPacket { bytes: magic_bytes };
+        MagicPacket { bytes: magic_bytes }
     }
 
-    /// Parse a MAC-string into a packet.
-    /// The MAC-string should be 17 characters long, separated by colons (i.e. XX:XX:XX:XX:XX:XX)
-    pub fn from_str(mac_str: &str) -> Result<MagicPacket, Box<dyn Error>> {
-        let mac_bytes = MagicPacket::parse_macstr(mac_str, ':')?;
-        return Ok(MagicPacket::new(&mac_bytes));
+    /// Create new MagicPacket from a raw 6-byte MAC address, with a 4 or 6 byte SecureOn
+    /// password appended to it.
+    pub fn new_secure(mac_bytes: &[u8; 6], password: &[u8]) -> Result<MagicPacket, WakerError> {
+        if password.len() != 4 && password.len() != 6 {
+            return Err(WakerError::Parse(format!("SecureOn password must be 4 or 6 bytes, got {}", password.len())));
+        }
+        let mut packet = MagicPacket::new(mac_bytes);
+        packet.bytes.extend_from_slice(password);
+        Ok(packet)
     }
 
     // This method is a bit allocation heavy.
-    pub fn parse_macstr<S: AsRef<str>>(mac_str: S, sep: char) -> Result<Box<[u8; 6]>, Box<dyn Error>> {
-        let sanitized_macstr = sanitize(mac_str.as_ref(), sanitizers::AddrType::MAC).unwrap();
-        let bytes_split: Vec<u8> = sanitized_macstr.split(sep)
-            .flat_map(|x| hex::decode(x).expect("Invalid mac!"))
-            .collect();
+    pub fn parse_macstr<S: AsRef<str>>(mac_str: S, sep: char) -> Result<Box<[u8; 6]>, WakerError> {
+        let invalid = || WakerError::Parse(format!("Invalid MAC address: {}", mac_str.as_ref()));
+        let sanitized_macstr = sanitize(mac_str.as_ref(), sanitizers::AddrType::MAC, sanitizers::Mode::Strict)?;
+        let mut bytes_split: Vec<u8> = Vec::with_capacity(6);
+        for x in sanitized_macstr.split(sep) {
+            bytes_split.extend(hex::decode(x).map_err(|_| invalid())?);
+        }
 
-        let arr: [u8; 6] = bytes_split.try_into().unwrap();
+        let arr: [u8; 6] = bytes_split.try_into().map_err(|_| invalid())?;
         Ok(Box::new(arr))
     }
 
-    /// Send packet to/from specific address/interface
+    /// Parse a SecureOn password string into raw bytes.
+    /// Six byte passwords are written like a MAC address (XX:XX:XX:XX:XX:XX, dashes or no
+    /// separators work as well), four byte passwords either as hex (XX:XX:XX:XX) or in dotted
+    /// decimal form like an IPv4 address (i.e. 192.168.1.1).
+    pub fn parse_password<S: AsRef<str>>(password: S) -> Result<Vec<u8>, WakerError> {
+        let password = password.as_ref().trim();
+        let bytes: Vec<u8> = if password.contains('.') {
+            password
+                .split('.')
+                .map(|byte| byte.parse::<u8>())
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| WakerError::Parse(format!("Invalid SecureOn password: {}", password)))?
+        } else {
+            let hex_str: String = password.chars().filter(|c| *c != ':' && *c != '-').collect();
+            hex::decode(&hex_str).map_err(|_| WakerError::Parse(format!("Invalid SecureOn password: {}", password)))?
+        };
+        if bytes.len() != 4 && bytes.len() != 6 {
+            return Err(WakerError::Parse(format!("SecureOn password must be 4 or 6 bytes: {}", password)));
+        }
+        Ok(bytes)
+    }
+
+    /// Send packet to/from specific address/interface, over IPv6 if the addresses are IPv6
     pub fn send_to<A: ToSocketAddrs>(&self, to_addr: A, from_addr: A) -> std::io::Result<()> {
         let socket = UdpSocket::bind(from_addr)?;
-        socket.set_broadcast(true)?;
+        if socket.local_addr()?.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
         socket.send_to(&self.bytes, to_addr)?;
         Ok(())
     }
 
-    /// Send package from whatever interface the os picks
-    pub fn send(&self) -> std::io::Result<()> {
-        self.send_to(
-            (Ipv4Addr::new(0xFF, 0xFF, 0xFF, 0xFF), 9),
-            (Ipv4Addr::new(0x0, 0x0, 0x0, 0x0), 0),
-        )
+    /// Send packet to/from specific address, with the socket bound to the named network device.
+    /// Binding to a device usually requires root or CAP_NET_RAW.
+    #[cfg(target_os = "linux")]
+    pub fn send_to_device<A: ToSocketAddrs>(&self, to_addr: A, from_addr: A, interface: &str) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(from_addr)?;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                interface.as_ptr() as *const libc::c_void,
+                interface.len() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if socket.local_addr()?.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+        socket.send_to(&self.bytes, to_addr)?;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn send_to_device<A: ToSocketAddrs>(&self, _to_addr: A, _from_addr: A, _interface: &str) -> std::io::Result<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "Binding to an interface is only supported on Linux"))
+    }
+
+    /// Send packet as a raw Ethernet frame (EtherType 0x0842) out of the named interface,
+    /// bypassing UDP/IP entirely
+    pub fn send_ethernet(&self, interface: &str) -> std::io::Result<()> {
+        let src_mac = ethernet::interface_mac(interface)?;
+        let frame = ethernet::build_frame(&ethernet::BROADCAST_MAC, &src_mac, &self.bytes);
+        ethernet::send_frame(interface, &frame)
+    }
+}
+
+impl FromStr for MagicPacket {
+    type Err = WakerError;
+
+    /// Parse a MAC-string into a packet.
+    /// The MAC-string should be 17 characters long, separated by colons (i.e. XX:XX:XX:XX:XX:XX)
+    fn from_str(mac_str: &str) -> Result<MagicPacket, WakerError> {
+        let mac_bytes = MagicPacket::parse_macstr(mac_str, ':')?;
+        Ok(MagicPacket::new(&mac_bytes))
     }
 }
 
@@ -102,4 +212,76 @@ mod tests {
         let mp2 = MagicPacket::parse_macstr("10:10:10:10:10:10", ':').unwrap();
         assert_eq!([0x10; 6], *mp2);
     }
+
+    #[test]
+    fn test_parse_invalid() {
+        // Wrong separator for the sanitized string, must not panic
+        assert!(matches!(MagicPacket::parse_macstr("10:10:10:10:10:10", '-'), Err(WakerError::Parse(_))));
+        assert!(MagicPacket::from_str("").is_err());
+    }
+
+    #[test]
+    fn packet_layout() {
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let packet = MagicPacket::new(&mac);
+        assert_eq!(102, packet.bytes.len());
+        assert_eq!([0xFF; 6], packet.bytes[..6]);
+        for chunk in packet.bytes[6..].chunks(6) {
+            assert_eq!(mac, chunk);
+        }
+    }
+
+    #[test]
+    fn secure_packet_layout() {
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let plain = MagicPacket::new(&mac);
+
+        let packet4 = MagicPacket::new_secure(&mac, &[0xC0, 0xA8, 0x01, 0x01]).unwrap();
+        assert_eq!(106, packet4.bytes.len());
+        assert_eq!(plain.bytes[..], packet4.bytes[..102]);
+        assert_eq!([0xC0, 0xA8, 0x01, 0x01], packet4.bytes[102..]);
+
+        let packet6 = MagicPacket::new_secure(&mac, &[0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]).unwrap();
+        assert_eq!(108, packet6.bytes.len());
+        assert_eq!(plain.bytes[..], packet6.bytes[..102]);
+        assert_eq!([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF], packet6.bytes[102..]);
+
+        assert!(MagicPacket::new_secure(&mac, &[0x00; 5]).is_err());
+        assert!(MagicPacket::new_secure(&mac, &[]).is_err());
+    }
+
+    #[test]
+    fn test_directed_broadcast() {
+        let ip = Ipv4Addr::new(192, 168, 1, 10);
+        assert_eq!(Ipv4Addr::new(192, 168, 1, 255), directed_broadcast(ip, 24));
+        assert_eq!(Ipv4Addr::new(192, 168, 255, 255), directed_broadcast(ip, 16));
+        assert_eq!(Ipv4Addr::new(192, 168, 1, 15), directed_broadcast(ip, 29));
+        assert_eq!(Ipv4Addr::new(255, 255, 255, 255), directed_broadcast(ip, 0));
+        assert_eq!(ip, directed_broadcast(ip, 32));
+        assert_eq!(Ipv4Addr::new(10, 127, 255, 255), directed_broadcast(Ipv4Addr::new(10, 1, 2, 3), 9));
+    }
+
+    #[test]
+    fn link_local_scope() {
+        assert!(is_link_local(&ALL_NODES));
+        assert!(is_link_local(&"fe80::1".parse().unwrap()));
+        assert!(is_link_local(&"febf::1".parse().unwrap()));
+        assert!(is_link_local(&"ff12::1".parse().unwrap())); // Transient, still link scope
+        assert!(!is_link_local(&"ff05::1".parse().unwrap()));
+        assert!(!is_link_local(&"fd00::1".parse().unwrap()));
+        assert!(!is_link_local(&"2001:db8::1".parse().unwrap()));
+        assert!(interface_index("lo").is_some());
+        assert_eq!(None, interface_index("no-such-interface0"));
+    }
+
+    #[test]
+    fn test_parse_password() {
+        assert_eq!(vec![0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF], MagicPacket::parse_password("aa:bb:cc:dd:ee:ff").unwrap());
+        assert_eq!(vec![0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF], MagicPacket::parse_password("AA-BB-CC-DD-EE-FF").unwrap());
+        assert_eq!(vec![0x01, 0x02, 0x03, 0x04], MagicPacket::parse_password("01:02:03:04").unwrap());
+        assert_eq!(vec![192, 168, 1, 1], MagicPacket::parse_password("192.168.1.1").unwrap());
+        assert!(MagicPacket::parse_password("aa:bb:cc").is_err());
+        assert!(MagicPacket::parse_password("192.168.1.256").is_err());
+        assert!(MagicPacket::parse_password("zz:zz:zz:zz").is_err());
+    }
 }
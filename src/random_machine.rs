@@ -31,7 +31,7 @@ fn random_name() -> String {
 }
 
 pub fn random_host() -> Host {
-    return Host::new(random_name(), random_mac(), random_ip());
+    return Host::new(random_name(), random_mac().parse().unwrap(), random_ip().parse().unwrap());
 }
 
 #[cfg(test)]
@@ -1,44 +1,168 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::error::WakerError;
+use crate::mac::MacAddress;
+
 /// For use as parameter in the sanitize function
 pub enum AddrType {
     MAC,
     IPv4,
+    IPv6,
+}
+
+/// How forgiving the parsers should be
+#[derive(Clone, Copy)]
+pub enum Mode {
+    /// Only the common notations are accepted: colon (aa:bb:cc:dd:ee:ff), dash
+    /// (aa-bb-cc-dd-ee-ff), Cisco dotted (aabb.ccdd.eeff) and bare hex (aabbccddeeff) for MACs,
+    /// dotted decimal without leading zeros for IPv4 and the standard notation for IPv6.
+    Strict,
+    /// Meant for interactive prompts. Tolerates surrounding whitespace, mixed separators,
+    /// single digit MAC groups (a:b:c:d:e:f), leading zeros or spaces in IPv4 octets and IPv6
+    /// addresses in brackets ([fe80::1]). Wrong lengths are still rejected.
+    Lenient,
 }
 
-/// Takes an AddrType enum and returns an Option<String> containing the sanitized string.
-/// Returns None if failed to sanitize.
-/// This is a very permissive sanitizer, and it will parse even the most malformatted strings
-/// It is guaranteed to return a valid MAC/IP
-pub fn sanitize(address: &str, addr_type: AddrType) -> Option<String> {
+// Characters accepted between MAC groups in lenient mode
+const LENIENT_MAC_SEPARATORS: [char; 5] = [':', '-', '.', ' ', '_'];
+
+/// Takes an AddrType enum and returns the address in its canonical string form,
+/// XX:XX:XX:XX:XX:XX for MAC addresses, dotted decimal for IPv4 and compressed hex for IPv6.
+/// Returns a descriptive WakerError::Parse if the address can not be parsed in the given mode.
+pub fn sanitize(address: &str, addr_type: AddrType, mode: Mode) -> Result<String, WakerError> {
     match addr_type {
-        AddrType::MAC => {
-            let mut mac_str = String::new();
-            for c in address.to_string().chars() {
-                if c.is_digit(16) {
-                    mac_str.push(c);
-                    if mac_str.len() == 12 { break; }
-                }
-            }
-            mac_str.insert(10, ':');
-            mac_str.insert(8, ':');
-            mac_str.insert(6, ':');
-            mac_str.insert(4, ':');
-            mac_str.insert(2, ':');
-            return Some(mac_str);
+        AddrType::MAC => parse_mac(address, mode).map(|mac| mac.to_string()),
+        AddrType::IPv4 => parse_ipv4(address, mode).map(|ip| ip.to_string()),
+        AddrType::IPv6 => parse_ipv6(address, mode).map(|ip| ip.to_string()),
+    }
+}
+
+/// Parses a MAC address in any of the notations accepted by the given mode
+pub fn parse_mac(address: &str, mode: Mode) -> Result<MacAddress, WakerError> {
+    let digits = match mode {
+        Mode::Strict => mac_digits_strict(address),
+        Mode::Lenient => mac_digits_lenient(address),
+    }
+    .map_err(|why| WakerError::Parse(format!("Invalid MAC address \"{}\": {}", address, why)))?;
+
+    let mut bytes = [0u8; 6];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        // Only ascii hex digits are left at this point, so this can not fail
+        *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).unwrap_or_default();
+    }
+    Ok(MacAddress::new(bytes))
+}
+
+/// Parses a dotted decimal IPv4 address
+pub fn parse_ipv4(address: &str, mode: Mode) -> Result<Ipv4Addr, WakerError> {
+    let invalid = |why: String| WakerError::Parse(format!("Invalid IPv4 address \"{}\": {}", address, why));
+    let trimmed = match mode {
+        Mode::Strict => address,
+        Mode::Lenient => address.trim(),
+    };
+    let octets: Vec<&str> = trimmed.split('.').collect();
+    if octets.len() != 4 {
+        return Err(invalid(format!("expected 4 octets, got {}", octets.len())));
+    }
+
+    let mut bytes = [0u8; 4];
+    for (byte, octet) in bytes.iter_mut().zip(octets) {
+        let octet = match mode {
+            Mode::Strict => octet,
+            Mode::Lenient => octet.trim(),
+        };
+        if octet.is_empty() || !octet.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid(format!("\"{}\" is not a decimal octet", octet)));
         }
-        AddrType::IPv4 => {
-            let mut bytes: Vec<u8> = Vec::new(); // Explicit type to avoid any typing errors
-            let ip_str = address.to_string();
-            let byte_list = ip_str.split('.');
-            for byte_base10_str in byte_list {
-                match byte_base10_str.parse::<u8>() {
-                    Ok(byte) => bytes.push(byte),
-                    Err(_what) => continue,
-                }
-                // bytes.push(byte_base10_str.parse::<u8>().unwrap());
+        if let Mode::Strict = mode {
+            if octet.len() > 1 && octet.starts_with('0') {
+                return Err(invalid(format!("octet \"{}\" has leading zeros", octet)));
             }
-            return Some(format!("{}.{}.{}.{}", bytes[0], bytes[2], bytes[2], bytes[3]));
         }
+        *byte = octet
+            .parse::<u8>()
+            .map_err(|_| invalid(format!("octet \"{}\" is out of range", octet)))?;
+    }
+    Ok(Ipv4Addr::from(bytes))
+}
+
+/// Parses an IPv6 address in standard notation (RFC 4291), i.e. fe80::1 or ::ffff:10.0.0.1
+pub fn parse_ipv6(address: &str, mode: Mode) -> Result<Ipv6Addr, WakerError> {
+    let invalid = |why: &str| WakerError::Parse(format!("Invalid IPv6 address \"{}\": {}", address, why));
+    let trimmed = match mode {
+        Mode::Strict => address,
+        Mode::Lenient => {
+            let trimmed = address.trim();
+            trimmed.strip_prefix('[').and_then(|inner| inner.strip_suffix(']')).unwrap_or(trimmed).trim()
+        }
+    };
+    // Zone indexes (fe80::1%eth0) name an interface of this machine, the host's interface is
+    // used as the scope instead
+    if trimmed.contains('%') {
+        return Err(invalid("zone indexes are not stored, set the interface of the host instead"));
+    }
+    if let Some(c) = trimmed.chars().find(|c| !c.is_ascii_hexdigit() && *c != ':' && *c != '.') {
+        return Err(invalid(&format!("unexpected character '{}'", c)));
+    }
+    trimmed.parse().map_err(|_| invalid("expected eight groups of hex digits, :: standing in for zeros"))
+}
+
+/// Parses an IPv4 address as parse_ipv4 does, or an IPv6 address as parse_ipv6 does
+pub fn parse_ip(address: &str, mode: Mode) -> Result<IpAddr, WakerError> {
+    if address.contains(':') {
+        return parse_ipv6(address, mode).map(IpAddr::V6);
+    }
+    parse_ipv4(address, mode).map(IpAddr::V4)
+}
+
+/// Returns the 12 hex digits of a MAC written in one of the strict notations
+fn mac_digits_strict(address: &str) -> Result<String, String> {
+    let separators: Vec<char> = [':', '-', '.'].iter().copied().filter(|s| address.contains(*s)).collect();
+    if let Some(c) = address.chars().find(|c| !c.is_ascii_hexdigit() && !separators.contains(c)) {
+        return Err(format!("unexpected character '{}'", c));
+    }
+
+    let (groups, width): (Vec<&str>, usize) = match separators.as_slice() {
+        [] => (vec![address], 12),
+        [':'] => (address.split(':').collect(), 2),
+        ['-'] => (address.split('-').collect(), 2),
+        ['.'] => (address.split('.').collect(), 4),
+        _ => return Err("mixed separators".to_string()),
+    };
+
+    let digits = groups.concat();
+    if digits.len() != 12 {
+        return Err(format!("expected 12 hex digits (6 bytes), got {}", digits.len()));
+    }
+    if groups.iter().any(|group| group.len() != width) {
+        return Err(format!("every group should be {} hex digits", width));
+    }
+    Ok(digits)
+}
+
+/// Returns the 12 hex digits of a MAC, forgiving sloppy formatting
+fn mac_digits_lenient(address: &str) -> Result<String, String> {
+    let address = address.trim();
+    if let Some(c) = address.chars().find(|c| !c.is_ascii_hexdigit() && !LENIENT_MAC_SEPARATORS.contains(c)) {
+        return Err(format!("unexpected character '{}'", c));
+    }
+
+    let groups: Vec<&str> = address
+        .split(|c| LENIENT_MAC_SEPARATORS.contains(&c))
+        .filter(|group| !group.is_empty())
+        .collect();
+
+    // Six short groups are single bytes, some tools drop the leading zero (a:b:c:d:e:f)
+    let digits = if groups.len() == 6 && groups.iter().all(|group| group.len() <= 2) {
+        groups.iter().map(|group| format!("{:0>2}", group)).collect::<String>()
+    } else {
+        groups.concat()
+    };
+
+    if digits.len() != 12 {
+        return Err(format!("expected 12 hex digits (6 bytes), got {}", digits.len()));
     }
+    Ok(digits)
 }
 
 #[cfg(test)]
@@ -48,26 +172,109 @@ mod tests {
     fn sanitize_mac() {
         let macstr = String::from("FFFFFFFFFFFF");
         let formatted = String::from("FF:FF:FF:FF:FF:FF");
-        assert_eq!(formatted, sanitize(&macstr, AddrType::MAC).unwrap());
+        assert_eq!(formatted, sanitize(&macstr, AddrType::MAC, Mode::Strict).unwrap());
     }
 
     #[test]
-    // No comparison check, just check so the length is correct
+    // Garbage used to be truncated into a valid looking MAC, it must be rejected in either mode
     fn sanitize_mac_garbage() {
         let macstr = String::from("sdakjaojoiwjvoievoijevioqjoijeriojkljlknxxx218913981389981jixjxxjk1kj1k");
-        assert_eq!(17, sanitize(&macstr, AddrType::MAC).unwrap().len());
+        assert!(sanitize(&macstr, AddrType::MAC, Mode::Strict).is_err());
+        assert!(sanitize(&macstr, AddrType::MAC, Mode::Lenient).is_err());
     }
     #[test]
     fn sanitize_ip() {
         let ipstr = String::from("255.255.255.255");
         let formatted = String::from("255.255.255.255");
-        assert_eq!(formatted, sanitize(&ipstr, AddrType::IPv4).unwrap());
+        assert_eq!(formatted, sanitize(&ipstr, AddrType::IPv4, Mode::Strict).unwrap());
     }
 
     #[test]
     fn sanitize_ip_garbage() {
         let ipstr = String::from("asdafasd.255.255.asdakfjjkjkfjk.255.255.asdafa");
-        let formatted = String::from("255.255.255.255");
-        assert_eq!(formatted, sanitize(&ipstr, AddrType::IPv4).unwrap());
+        assert!(sanitize(&ipstr, AddrType::IPv4, Mode::Strict).is_err());
+        assert!(sanitize(&ipstr, AddrType::IPv4, Mode::Lenient).is_err());
+    }
+
+    #[test]
+    fn mac_notations() {
+        let expected = MacAddress::new([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x0F]);
+        for notation in ["aa:bb:cc:dd:ee:0f", "AA-BB-CC-DD-EE-0F", "aabb.ccdd.ee0f", "AABBCCDDEE0F"] {
+            assert_eq!(expected, parse_mac(notation, Mode::Strict).unwrap());
+            assert_eq!(expected, parse_mac(notation, Mode::Lenient).unwrap());
+        }
+    }
+
+    #[test]
+    fn mac_strict_rejects() {
+        for bad in [
+            "aa:bb:cc:dd:ee",       // Too short
+            "aa:bb:cc:dd:ee:ff:00", // Too long
+            "aa:bb:cc:dd:ee:fg",    // Not hex
+            "aa:bb-cc:dd:ee:ff",    // Mixed separators
+            "aab:bcc:dde:eff",      // Wrong grouping
+            "a:b:c:d:e:f",          // Short groups
+            " aa:bb:cc:dd:ee:ff",   // Whitespace
+            "",
+        ] {
+            assert!(matches!(parse_mac(bad, Mode::Strict), Err(WakerError::Parse(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn mac_lenient() {
+        let expected = MacAddress::new([0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F]);
+        assert_eq!(expected, parse_mac("a:b:c:d:e:f", Mode::Lenient).unwrap());
+        assert_eq!(expected, parse_mac("  0a-0b:0c 0d_0e.0f\n", Mode::Lenient).unwrap());
+        assert!(parse_mac("0a:0b:0c:0d:0e", Mode::Lenient).is_err());
+        assert!(parse_mac("0a:0b:0c:0d:0e:0f:10", Mode::Lenient).is_err());
+    }
+
+    #[test]
+    fn mac_error_is_descriptive() {
+        let what = parse_mac("aa:bb:cc:dd:ee", Mode::Strict).unwrap_err().to_string();
+        assert!(what.contains("aa:bb:cc:dd:ee") && what.contains("got 10"), "{}", what);
+    }
+
+    #[test]
+    fn ipv4_strict_and_lenient() {
+        let expected = Ipv4Addr::new(192, 168, 1, 10);
+        assert_eq!(expected, parse_ipv4("192.168.1.10", Mode::Strict).unwrap());
+        assert_eq!(expected, parse_ipv4(" 192.168.001.010 ", Mode::Lenient).unwrap());
+        assert!(parse_ipv4("192.168.001.010", Mode::Strict).is_err());
+        assert!(parse_ipv4("192.168.1", Mode::Strict).is_err());
+        assert!(parse_ipv4("192.168.1", Mode::Lenient).is_err());
+        assert!(parse_ipv4("192.168.1.256", Mode::Lenient).is_err());
+        assert!(parse_ipv4("192.168..1", Mode::Lenient).is_err());
+
+        let what = parse_ipv4("10.0.1", Mode::Strict).unwrap_err().to_string();
+        assert!(what.contains("expected 4 octets, got 3"), "{}", what);
+    }
+
+    #[test]
+    fn ip_either_family() {
+        assert_eq!("10.0.0.1".parse::<IpAddr>().unwrap(), parse_ip("10.0.0.1", Mode::Strict).unwrap());
+        assert_eq!("fe80::1".parse::<IpAddr>().unwrap(), parse_ip("fe80::1", Mode::Strict).unwrap());
+        assert_eq!("fe80::1".parse::<IpAddr>().unwrap(), parse_ip(" fe80::1 ", Mode::Lenient).unwrap());
+        assert!(parse_ip(" fe80::1", Mode::Strict).is_err());
+        assert!(parse_ip("fe80:::1", Mode::Strict).is_err());
+    }
+
+    #[test]
+    fn ipv6_strict_and_lenient() {
+        let expected: Ipv6Addr = "fe80::1".parse().unwrap();
+        assert_eq!(expected, parse_ipv6("fe80::1", Mode::Strict).unwrap());
+        assert_eq!(expected, parse_ipv6("FE80:0:0:0:0:0:0:1", Mode::Strict).unwrap());
+        assert_eq!(expected, parse_ipv6(" [fe80::1] ", Mode::Lenient).unwrap());
+        assert_eq!("ff02::1", sanitize("FF02:0::1", AddrType::IPv6, Mode::Strict).unwrap());
+        assert!(parse_ipv6("[fe80::1]", Mode::Strict).is_err());
+        assert!(parse_ipv6("10.0.0.1", Mode::Strict).is_err());
+        assert!(parse_ipv6("fe80::1::2", Mode::Lenient).is_err());
+        assert!(parse_ipv6("fe80::1:2:3:4:5:6:7", Mode::Strict).is_err());
+
+        let what = parse_ipv6("fe80::1%eth0", Mode::Lenient).unwrap_err().to_string();
+        assert!(what.contains("zone indexes"), "{}", what);
+        let what = parse_ipv6("fe80::g", Mode::Strict).unwrap_err().to_string();
+        assert!(what.contains("unexpected character 'g'"), "{}", what);
     }
 }
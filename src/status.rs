@@ -0,0 +1,424 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::error::WakerError;
+use crate::host::Host;
+
+// Liveness probing. Each IP of a host is first sent an ICMP echo request through an unprivileged
+// datagram socket (Linux allows these for groups in net.ipv4.ping_group_range). If that is not
+// permitted, or the host does not answer, a TCP connect is attempted on a list of common ports.
+// Both an accepted and a refused connection mean something answered at that address. However many
+// hosts are probed, at most PROBE_THREADS probes run at a time.
+
+/// Whether a host seems to be awake
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Up,      // Answered a probe
+    Down,    // Did not answer any probe in time
+    Unknown, // Nothing to probe, i.e. no IP addresses configured
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            Status::Up => "up",
+            Status::Down => "down",
+            Status::Unknown => "unknown",
+        };
+        // Pad manually, so column widths given by the caller are honored
+        f.pad(status)
+    }
+}
+
+/// Ports tried when ICMP is unavailable or unanswered: ssh, http, https, smb and rdp
+pub const DEFAULT_PROBE_PORTS: [u16; 5] = [22, 80, 443, 445, 3389];
+
+/// Tunes how hosts are probed
+#[derive(Clone)]
+pub struct ProbeOptions {
+    pub timeout: Duration, // Per probe
+    pub icmp: bool,        // Try ICMP echo before falling back to TCP
+    pub tcp_ports: Vec<u16>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        ProbeOptions {
+            timeout: Duration::from_millis(500),
+            icmp: true,
+            tcp_ports: DEFAULT_PROBE_PORTS.to_vec(),
+        }
+    }
+}
+
+/// Most probes in flight at once, each one a thread waiting for a connect or an echo reply
+pub const PROBE_THREADS: usize = 64;
+
+/// Probes every IP of a host concurrently, the host is up if any of them answer
+pub fn probe_host(host: &Host, options: &ProbeOptions) -> Status {
+    probe_all(std::slice::from_ref(host), options)[0]
+}
+
+/// Probes all hosts concurrently, returning their statuses in the same order.
+/// A host is up if any of its IPs answer. Link-local IPv6 ones are probed on its interface.
+pub fn probe_all(hosts: &[Host], options: &ProbeOptions) -> Vec<Status> {
+    let addresses: Vec<Vec<SocketAddr>> = hosts.iter().map(|host| host.probe_addrs()).collect();
+    probe_addresses(&addresses, options)
+}
+
+/// Probes groups of addresses, a group is up if any of its addresses answer. ICMP goes first and
+/// TCP ports are only tried for the groups that did not answer it.
+fn probe_addresses(groups: &[Vec<SocketAddr>], options: &ProbeOptions) -> Vec<Status> {
+    let mut up = vec![false; groups.len()];
+    if options.icmp {
+        let echoes: Vec<(usize, SocketAddr)> = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, addrs)| addrs.iter().map(move |addr| (group, *addr)))
+            .collect();
+        run_probes(&echoes, &mut up, |addr| matches!(icmp_echo(addr, options.timeout), Ok(true)));
+    }
+    let connects: Vec<(usize, SocketAddr)> = groups
+        .iter()
+        .enumerate()
+        .filter(|(group, _)| !up[*group])
+        .flat_map(|(group, addrs)| {
+            addrs.iter().flat_map(move |addr| {
+                options.tcp_ports.iter().map(move |port| {
+                    let mut target = *addr;
+                    target.set_port(*port);
+                    (group, target)
+                })
+            })
+        })
+        .collect();
+    run_probes(&connects, &mut up, |addr| match TcpStream::connect_timeout(&addr, options.timeout) {
+        Ok(_) => true,
+        Err(what) => what.kind() == io::ErrorKind::ConnectionRefused,
+    });
+    groups
+        .iter()
+        .zip(up)
+        .map(|(addrs, up)| match (addrs.is_empty(), up) {
+            (true, _) => Status::Unknown,
+            (false, true) => Status::Up,
+            (false, false) => Status::Down,
+        })
+        .collect()
+}
+
+/// Runs the probes on at most PROBE_THREADS threads, which take the next probe until none are
+/// left. Marks the group of every probe that answered, and skips probes of groups already marked.
+fn run_probes<T, F>(probes: &[(usize, T)], up: &mut [bool], answers: F)
+where
+    T: Copy + Sync,
+    F: Fn(T) -> bool + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let answered: Vec<AtomicBool> = up.iter().map(|up| AtomicBool::new(*up)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..probes.len().min(PROBE_THREADS) {
+            scope.spawn(|| {
+                while let Some((group, target)) = probes.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if !answered[*group].load(Ordering::Relaxed) && answers(*target) {
+                        answered[*group].store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    for (up, answered) in up.iter_mut().zip(answered) {
+        *up = answered.into_inner();
+    }
+}
+
+/// How long to keep trying to wake a host before giving up
+#[derive(Clone)]
+pub struct WaitOptions {
+    pub timeout: Duration,
+    pub interval: Duration, // Between resent magic packets
+}
+
+/// Sends magic packets through `wake` every interval, until the host answers a probe or the
+/// timeout expires. Returns how long it took for the host to come up.
+pub fn wait_for_host<F>(host: &Host, wait: &WaitOptions, probe: &ProbeOptions, mut wake: F) -> Result<Duration, WakerError>
+where
+    F: FnMut() -> Result<(), WakerError>,
+{
+    let mut waiting = Waiting::start(host, wait, probe)?;
+    loop {
+        if waiting.wake_due() {
+            wake()?;
+        }
+        match waiting.after_probe(probe_host(host, probe) == Status::Up)? {
+            WaitStep::Done(took) => return Ok(took),
+            WaitStep::Sleep(pause) => std::thread::sleep(pause),
+        }
+    }
+}
+
+/// What follows a probe while waiting for a host
+pub enum WaitStep {
+    Done(Duration),  // The host is up, after this long
+    Sleep(Duration), // Probe again after this pause, waking first if that is due by then
+}
+
+/// The schedule of waiting for a host to come up, shared by the blocking and the async drivers:
+/// wake when due, probe, then see what follows
+pub struct Waiting {
+    name: String,
+    timeout: Duration,
+    interval: Duration,
+    probe_timeout: Duration,
+    start: Instant,
+    next_wake: Instant,
+}
+
+impl Waiting {
+    /// Starts the clock. Fails if the host has no IP addresses to probe.
+    pub fn start(host: &Host, wait: &WaitOptions, probe: &ProbeOptions) -> Result<Waiting, WakerError> {
+        if host.ips.is_empty() {
+            return Err(WakerError::Config(format!("{} has no IP addresses to probe", host.name)));
+        }
+        let start = Instant::now();
+        Ok(Waiting {
+            name: host.name.clone(),
+            timeout: wait.timeout,
+            interval: wait.interval,
+            probe_timeout: probe.timeout,
+            start,
+            next_wake: start,
+        })
+    }
+
+    /// Whether magic packets are due, moving on to the next resend if they are
+    pub fn wake_due(&mut self) -> bool {
+        if Instant::now() < self.next_wake {
+            return false;
+        }
+        self.next_wake += self.interval;
+        true
+    }
+
+    /// Done if the probe was answered, a timeout once the deadline passed, else a pause
+    pub fn after_probe(&self, up: bool) -> Result<WaitStep, WakerError> {
+        if up {
+            return Ok(WaitStep::Done(self.start.elapsed()));
+        }
+        let now = Instant::now();
+        let deadline = self.start + self.timeout;
+        if now >= deadline {
+            return Err(WakerError::Timeout(format!("{} did not come up within {:?}", self.name, self.timeout)));
+        }
+        // Probes that fail fast, i.e. unreachable networks, should not spin
+        Ok(WaitStep::Sleep(self.next_wake.min(deadline).saturating_duration_since(now).min(self.probe_timeout)))
+    }
+}
+
+/// Sends a single ICMP echo request and waits for the reply. The port of `addr` is ignored.
+/// Returns an error if unprivileged ICMP sockets are not available.
+#[cfg(target_os = "linux")]
+pub fn icmp_echo(addr: SocketAddr, timeout: Duration) -> io::Result<bool> {
+    use std::os::unix::io::FromRawFd;
+
+    let (domain, protocol, request_type, reply_type) = match addr.ip() {
+        IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP, 8u8, 0u8),
+        IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6, 128u8, 129u8),
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Ping sockets support the same sendto/recv calls as UDP sockets, the kernel fills in the
+    // identifier and only hands us replies matching it. UdpSocket takes ownership of the fd.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let sequence: u16 = (std::process::id() & 0xFFFF) as u16;
+    let request = echo_request(request_type, sequence);
+    socket.send_to(&request, addr)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; 128];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv(&mut buffer) {
+            Ok(len) if len >= 8 => {
+                let reply_sequence = u16::from_be_bytes([buffer[6], buffer[7]]);
+                if buffer[0] == reply_type && reply_sequence == sequence {
+                    return Ok(true);
+                }
+            }
+            Ok(_) => continue,
+            Err(what) if what.kind() == io::ErrorKind::WouldBlock || what.kind() == io::ErrorKind::TimedOut => {
+                return Ok(false)
+            }
+            Err(what) => return Err(what),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn icmp_echo(_addr: SocketAddr, _timeout: Duration) -> io::Result<bool> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "ICMP probing is only supported on Linux"))
+}
+
+/// Builds an ICMP echo request. The identifier is left for the kernel to fill in.
+fn echo_request(request_type: u8, sequence: u16) -> Vec<u8> {
+    let mut packet = vec![request_type, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(b"waker-ping");
+    let checksum = internet_checksum(&packet);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// RFC 1071 ones' complement checksum. ICMPv6 checksums are recomputed by the kernel anyway.
+fn internet_checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in bytes.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn tcp_only(ports: Vec<u16>) -> ProbeOptions {
+        ProbeOptions {
+            timeout: Duration::from_millis(200),
+            icmp: false,
+            tcp_ports: ports,
+        }
+    }
+
+    fn local_host() -> Host {
+        Host::new("Demo_Machine", "01:02:03:04:05:06".parse().unwrap(), "127.0.0.1".parse().unwrap())
+    }
+
+    #[test]
+    fn checksum() {
+        // Verifying a packet including its checksum yields zero
+        let request = echo_request(8, 0x1234);
+        assert_eq!(0, internet_checksum(&request));
+        assert_eq!(8, request[0]);
+        assert_eq!([0x12, 0x34], request[6..8]);
+    }
+
+    #[test]
+    fn tcp_listening_port_is_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(vec![Status::Up], probe_all(&[local_host()], &tcp_only(vec![port])));
+    }
+
+    #[test]
+    fn tcp_refused_port_is_up() {
+        // Grab a free port and close it again, so the connection is refused
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert_eq!(vec![Status::Up], probe_all(&[local_host()], &tcp_only(vec![port])));
+    }
+
+    #[test]
+    fn nothing_to_probe() {
+        let mut host = local_host();
+        assert_eq!(vec![Status::Down], probe_all(&[local_host()], &tcp_only(vec![])));
+        host.ips.clear();
+        assert_eq!(vec![Status::Unknown], probe_all(&[host], &tcp_only(vec![])));
+    }
+
+    #[test]
+    fn probe_all_keeps_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let up = Host::new("Up_Machine", "01:02:03:04:05:06".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        let mut unknown = Host::new("Unknown_Machine", "01:02:03:04:05:07".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        unknown.ips.clear();
+        let statuses = probe_all(&[unknown, up], &tcp_only(vec![port]));
+        assert_eq!(vec![Status::Unknown, Status::Up], statuses);
+    }
+
+    #[test]
+    fn more_probes_than_threads() {
+        // Refused, a listener that never accepts would run out of backlog
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let hosts: Vec<Host> = (0..PROBE_THREADS * 3).map(|_| local_host()).collect();
+        let statuses = probe_all(&hosts, &tcp_only(vec![port, port]));
+        assert!(statuses.iter().all(|status| *status == Status::Up));
+    }
+
+    #[test]
+    fn icmp_loopback() {
+        // Unprivileged ICMP may be disabled, in which case an error is expected rather than a hang
+        if let Ok(answered) = icmp_echo("127.0.0.1:0".parse().unwrap(), Duration::from_millis(500)) {
+            assert!(answered);
+        }
+    }
+
+    #[test]
+    fn wait_for_up_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host = Host::new("Up_Machine", "01:02:03:04:05:06".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        let wait = WaitOptions { timeout: Duration::from_secs(5), interval: Duration::from_secs(1) };
+        let mut sent = 0;
+        let waited = wait_for_host(&host, &wait, &tcp_only(vec![port]), || {
+            sent += 1;
+            Ok(())
+        });
+        assert!(waited.unwrap() < Duration::from_secs(1));
+        assert_eq!(1, sent);
+    }
+
+    #[test]
+    fn wait_times_out_and_resends() {
+        // Without any probes to answer, the host never comes up
+        let host = Host::new("Down_Machine", "01:02:03:04:05:06".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        let wait = WaitOptions { timeout: Duration::from_millis(250), interval: Duration::from_millis(100) };
+        let mut sent = 0;
+        let waited = wait_for_host(&host, &wait, &tcp_only(vec![]), || {
+            sent += 1;
+            Ok(())
+        });
+        assert!(matches!(waited, Err(WakerError::Timeout(_))));
+        assert!((2..=4).contains(&sent), "sent {} packets", sent);
+    }
+
+    #[test]
+    fn wait_needs_ips() {
+        let mut host = Host::new("Bare_Machine", "01:02:03:04:05:06".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        host.ips.clear();
+        let wait = WaitOptions { timeout: Duration::from_secs(1), interval: Duration::from_secs(1) };
+        assert!(matches!(wait_for_host(&host, &wait, &tcp_only(vec![]), || Ok(())), Err(WakerError::Config(_))));
+    }
+
+    #[test]
+    fn wait_stops_on_send_failure() {
+        let host = Host::new("Broken_Machine", "01:02:03:04:05:06".parse().unwrap(), "127.0.0.1".parse().unwrap());
+        let wait = WaitOptions { timeout: Duration::from_secs(5), interval: Duration::from_secs(1) };
+        let waited = wait_for_host(&host, &wait, &tcp_only(vec![]), || Err(WakerError::Parse("bad".to_string())));
+        assert!(matches!(waited, Err(WakerError::Parse(_))));
+    }
+
+    #[test]
+    fn status_column() {
+        assert_eq!("up     |", format!("{:<7}|", Status::Up));
+        assert_eq!("unknown", Status::Unknown.to_string());
+    }
+}
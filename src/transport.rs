@@ -0,0 +1,93 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::packet::MagicPacket;
+
+/// How a magic packet gets to its host
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    /// A UDP datagram. `to` is usually a broadcast address, but may be the host itself.
+    /// With a device, the socket is bound to that network interface.
+    Udp { to: SocketAddr, from: SocketAddr, device: Option<String> },
+    /// A raw Ethernet frame (EtherType 0x0842) broadcast out of an interface
+    Ethernet { interface: String },
+}
+
+/// Puts magic packets on the wire. Wake functions take one of these, so that what is sent can be
+/// observed or redirected, see MockTransport.
+pub trait Transport: Sync {
+    fn send(&self, packet: &MagicPacket, route: &Route) -> io::Result<()>;
+
+    /// Waits between repeated packets, see Pacing. Mocks record the pause instead of sleeping.
+    fn pause(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Sends packets through the operating system's sockets
+pub struct SystemTransport;
+
+impl Transport for SystemTransport {
+    fn send(&self, packet: &MagicPacket, route: &Route) -> io::Result<()> {
+        match route {
+            Route::Udp { to, from, device: None } => packet.send_to(*to, *from),
+            Route::Udp { to, from, device: Some(device) } => packet.send_to_device(*to, *from, device),
+            Route::Ethernet { interface } => packet.send_ethernet(interface),
+        }
+    }
+}
+
+type FailWhen = Box<dyn Fn(&Route) -> bool + Send + Sync>;
+
+/// Records packets instead of sending them, so tests can check exactly what would have gone out.
+/// Pauses do not sleep, they move a clock that every recorded packet is stamped with.
+#[derive(Default)]
+pub struct MockTransport {
+    sent: Mutex<Vec<(Duration, Route, Vec<u8>)>>,
+    clock: Mutex<Duration>,
+    fail_when: Option<FailWhen>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// A transport that fails to send, with a network error, on every route the predicate accepts.
+    /// Failed packets are not recorded.
+    pub fn failing<F>(fail_when: F) -> MockTransport
+    where
+        F: Fn(&Route) -> bool + Send + Sync + 'static,
+    {
+        MockTransport { fail_when: Some(Box::new(fail_when)), ..Default::default() }
+    }
+
+    /// Every packet sent so far, with its route, in the order they were sent
+    pub fn sent(&self) -> Vec<(Route, Vec<u8>)> {
+        let sent = self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        sent.iter().map(|(_, route, bytes)| (route.clone(), bytes.clone())).collect()
+    }
+
+    /// When every packet was sent, by the clock that only pause() moves, and where to
+    pub fn schedule(&self) -> Vec<(Duration, Route)> {
+        let sent = self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        sent.iter().map(|(time, route, _)| (*time, route.clone())).collect()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, packet: &MagicPacket, route: &Route) -> io::Result<()> {
+        if self.fail_when.as_ref().is_some_and(|fail_when| fail_when(route)) {
+            return Err(io::Error::other(format!("Mock failure sending to {:?}", route)));
+        }
+        let time = *self.clock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push((time, route.clone(), packet.bytes.clone()));
+        Ok(())
+    }
+
+    fn pause(&self, duration: Duration) {
+        *self.clock.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) += duration;
+    }
+}
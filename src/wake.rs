@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use crate::error::WakerError;
+use crate::host::Host;
+use crate::machines::WakeReport;
+use crate::pacing::Pacing;
+use crate::status::{self, ProbeOptions, WaitOptions};
+use crate::transport::{SystemTransport, Transport};
+
+/// Options that apply to every packet sent during a wake
+#[derive(Default)]
+pub struct WakeOptions {
+    pub ethernet: Option<String>, // Send raw Ethernet frames on this interface, overriding hosts
+    pub probe: ProbeOptions,      // How to find out which hosts are already up
+    pub wait: Option<WaitOptions>, // Keep waking until the hosts are up
+    pub exec: Option<String>,     // Shell command to run for every host that came up
+    pub pacing: Pacing,           // Overrides the pacing of every host
+    pub default_pacing: Pacing,   // Pacing of hosts that do not set their own, usually Machines::pacing
+}
+
+impl WakeOptions {
+    /// The pacing a host is woken with
+    pub fn pacing_for(&self, host: &Host) -> Pacing {
+        self.pacing.or(host.pacing()).or(self.default_pacing)
+    }
+}
+
+/// Wakes the given hosts. When waiting, every host is woken and probed concurrently until it is
+/// up, and the post-wake command is run for each host that came up. The report then holds how
+/// long each host took.
+pub fn wake_hosts(hosts: &[&Host], options: &WakeOptions) -> WakeReport {
+    wake_hosts_with(&SystemTransport, hosts, options)
+}
+
+/// Same as wake_hosts, handing the packets to the given transport
+pub fn wake_hosts_with(transport: &dyn Transport, hosts: &[&Host], options: &WakeOptions) -> WakeReport {
+    let ethernet = options.ethernet.as_deref();
+    let wait = match &options.wait {
+        Some(wait) => wait,
+        None => {
+            let results = hosts
+                .iter()
+                .map(|host| (host.name.clone(), host.wake_paced(transport, ethernet, &options.pacing_for(host)).map(|_| None)))
+                .collect();
+            return WakeReport { results };
+        }
+    };
+
+    let outcomes: Vec<Result<Duration, WakerError>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = hosts
+            .iter()
+            .map(|host| {
+                let pacing = options.pacing_for(host);
+                scope.spawn(move || status::wait_for_host(host, wait, &options.probe, || host.wake_paced(transport, ethernet, &pacing)))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(WakerError::Io(std::io::Error::other("Wake thread panicked")))))
+            .collect()
+    });
+
+    let mut results = Vec::new();
+    for (host, outcome) in hosts.iter().zip(outcomes) {
+        let result = outcome.and_then(|waited| {
+            if let Some(command) = &options.exec {
+                run_command(command, host)?;
+            }
+            Ok(Some(waited))
+        });
+        results.push((host.name.clone(), result));
+    }
+    WakeReport { results }
+}
+
+/// Runs a shell command for a host that just came up. The host name and first IP address are
+/// passed in the WAKER_HOST and WAKER_IP environment variables.
+pub fn run_command(command: &str, host: &Host) -> Result<(), WakerError> {
+    let mut shell = std::process::Command::new("sh");
+    shell.arg("-c").arg(command).env("WAKER_HOST", &host.name);
+    if let Some(ip) = host.ips.first() {
+        shell.env("WAKER_IP", ip.to_string());
+    }
+    let status = shell.status()?;
+    if !status.success() {
+        return Err(WakerError::Io(std::io::Error::other(format!("\"{}\" exited with {}", command, status))));
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+// Uses waker the way another crate would, through the public API only
+
+use waker::{ConfigFormat, Machines, MagicPacket, Selection, WakerError};
+
+#[test]
+fn config_round_trip_and_selection() {
+    let mut machines = Machines::default();
+    machines.add("nas", "aa-bb-cc-dd-ee-01".parse().unwrap(), Some("10.0.0.5".parse().unwrap()));
+    machines.add("desktop-1", "AA:BB:CC:DD:EE:02".parse().unwrap(), None).tags = vec!["office".to_string()];
+    machines.add("desktop-2", "AA:BB:CC:DD:EE:03".parse().unwrap(), None);
+
+    let text = ConfigFormat::Toml.serialize(&machines).unwrap();
+    let loaded = ConfigFormat::Toml.deserialize(&text).unwrap();
+    let selection = Selection {
+        targets: vec!["nas".to_string(), "desktop-*".to_string()],
+        tags: vec!["office".to_string()],
+        ..Default::default()
+    };
+    let names: Vec<String> = loaded.select(&selection).unwrap().into_iter().map(|host| host.name).collect();
+    assert_eq!(vec!["nas", "desktop-1", "desktop-2"], names);
+
+    let unknown = Selection { targets: vec!["printer".to_string()], ..Default::default() };
+    assert!(matches!(loaded.select(&unknown), Err(WakerError::Config(_))));
+}
+
+#[test]
+fn packets_for_hosts() {
+    let mut machines = Machines::new();
+    let host = machines.add("nas", "AA:BB:CC:DD:EE:01".parse().unwrap(), None);
+    host.password = Some("01:02:03:04:05:06".to_string());
+    let packets = host.packets().unwrap();
+    assert_eq!(1, packets.len());
+    assert_eq!(108, packets[0].bytes.len());
+
+    let plain: MagicPacket = "AA:BB:CC:DD:EE:01".parse().unwrap();
+    assert_eq!(packets[0].bytes[..102], plain.bytes[..]);
+}
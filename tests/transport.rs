@@ -0,0 +1,185 @@
+// Wakes hosts through the recording mock transport and checks exactly what went where
+
+use std::net::{SocketAddr, SocketAddrV6};
+use std::time::Duration;
+
+use waker::{ConfigFormat, Machines, MockTransport, Pacing, Route, WakeOptions, WakerError};
+
+fn udp(to: &str, from: &str, device: Option<&str>) -> Route {
+    Route::Udp {
+        to: to.parse::<SocketAddr>().unwrap(),
+        from: from.parse::<SocketAddr>().unwrap(),
+        device: device.map(|device| device.to_string()),
+    }
+}
+
+/// The demo hosts the unit tests use too
+fn demo_machines() -> Machines {
+    ConfigFormat::Json.deserialize(include_str!("fixtures/machines.json")).unwrap()
+}
+
+#[test]
+fn routes_per_host() {
+    let transport = MockTransport::new();
+    let report = demo_machines().wakeall_with(&transport, None);
+    assert!(report.into_result().is_ok());
+
+    let sent = transport.sent();
+    let routes: Vec<Route> = sent.iter().map(|(route, _)| route.clone()).collect();
+    let ethernet = Route::Ethernet { interface: "eth0".to_string() };
+    assert_eq!(
+        vec![
+            udp("255.255.255.255:9", "0.0.0.0:0", None),
+            udp("10.1.2.255:7", "10.1.2.1:0", None),
+            udp("192.168.9.255:9", "0.0.0.0:0", Some("eth1")),
+            ethernet.clone(),
+            ethernet,
+        ],
+        routes
+    );
+
+    // Header, then the MAC sixteen times, then the SecureOn password if there is one
+    let (_, plain) = &sent[0];
+    assert_eq!(102, plain.len());
+    assert_eq!([0xFF; 6], plain[..6]);
+    assert_eq!([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x01], plain[96..]);
+    let (_, second_mac) = &sent[4];
+    assert_eq!(108, second_mac.len());
+    assert_eq!([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x05], second_mac[96..102]);
+    assert_eq!([1, 2, 3, 4, 5, 6], second_mac[102..]);
+}
+
+#[test]
+fn ethernet_override() {
+    let transport = MockTransport::new();
+    let machines = demo_machines();
+    let hosts: Vec<_> = machines.list.iter().take(2).collect();
+    let options = WakeOptions {
+        ethernet: Some("br0".to_string()),
+        ..Default::default()
+    };
+    let report = waker::wake_hosts_with(&transport, &hosts, &options);
+    assert_eq!(2, report.results.len());
+    let bridge = Route::Ethernet { interface: "br0".to_string() };
+    assert!(transport.sent().iter().all(|(route, _)| *route == bridge));
+    assert_eq!(2, transport.sent().len());
+}
+
+#[test]
+fn failures_do_not_stop_the_rest() {
+    let transport = MockTransport::failing(|route| matches!(route, Route::Udp { device: Some(_), .. }));
+    let report = demo_machines().wakeall_with(&transport, None);
+    let failed: Vec<&str> = report.results.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| name.as_str()).collect();
+    assert_eq!(vec!["bound"], failed);
+    assert_eq!(4, transport.sent().len());
+    assert!(matches!(report.into_result(), Err(WakerError::Network(_))));
+}
+
+#[test]
+fn repeats_are_paced() {
+    let transport = MockTransport::new();
+    let mut machines = demo_machines();
+    machines.pacing = Pacing { repeat: Some(3), interval: Some(250), burst: None };
+    machines.list[1].repeat = Some(2);
+    let report = machines.wakeall_with(&transport, None);
+    assert!(report.into_result().is_ok());
+
+    // Rounds of one host are the interval apart, hosts follow each other without a pause
+    let times: Vec<u128> = transport.schedule().iter().map(|(time, _)| time.as_millis()).collect();
+    assert_eq!(vec![0, 250, 500, 500, 750, 750, 1000, 1250, 1250, 1250, 1500, 1500, 1750, 1750], times);
+
+    // The command line wins over the host, which wins over the config
+    let transport = MockTransport::new();
+    let hosts: Vec<_> = machines.list.iter().take(2).collect();
+    let options = WakeOptions {
+        pacing: Pacing { interval: Some(10), ..Default::default() },
+        default_pacing: machines.pacing,
+        ..Default::default()
+    };
+    waker::wake_hosts_with(&transport, &hosts, &options);
+    let times: Vec<Duration> = transport.schedule().iter().map(|(time, _)| *time).collect();
+    let millis = Duration::from_millis;
+    assert_eq!(vec![millis(0), millis(10), millis(20), millis(20), millis(30)], times);
+}
+
+#[test]
+fn burst_to_every_address() {
+    let transport = MockTransport::new();
+    let mut machines = demo_machines();
+    let directed = &mut machines.list[1];
+    directed.burst = Some(true);
+    directed.ips.push("fd00::3".parse().unwrap());
+    directed.ips.push("10.1.3.3".parse().unwrap());
+    directed.wake_with(&transport, None).unwrap();
+
+    let routes: Vec<Route> = transport.schedule().into_iter().map(|(_, route)| route).collect();
+    assert_eq!(
+        vec![
+            udp("10.1.2.255:7", "10.1.2.1:0", None),
+            udp("10.1.3.255:7", "10.1.2.1:0", None),
+            udp("255.255.255.255:7", "10.1.2.1:0", None),
+            udp("10.1.2.3:7", "10.1.2.1:0", None),
+            udp("[fd00::3]:7", "[::]:0", None),
+            udp("10.1.3.3:7", "10.1.2.1:0", None),
+        ],
+        routes
+    );
+
+    // Raw Ethernet frames have nowhere else to go
+    let layer2 = &mut machines.list[3];
+    layer2.burst = Some(true);
+    assert_eq!(1, layer2.routes(None, true).unwrap().len());
+}
+
+#[test]
+fn failed_destinations_do_not_fail_the_burst() {
+    let unicast = "10.1.2.3:7".parse::<SocketAddr>().unwrap();
+    let transport = MockTransport::failing(move |route| matches!(route, Route::Udp { to, .. } if *to == unicast));
+    let mut machines = demo_machines();
+    machines.list[1].burst = Some(true);
+    machines.list[1].repeat = Some(2);
+    assert!(machines.list[1].wake_with(&transport, None).is_ok());
+    assert_eq!(4, transport.sent().len());
+
+    // Unless nothing gets out at all, which does not wait for the remaining rounds either
+    let transport = MockTransport::failing(|_| true);
+    assert!(matches!(machines.list[1].wake_with(&transport, None), Err(WakerError::Network(_))));
+}
+
+#[test]
+fn ipv6_destinations() {
+    let transport = MockTransport::new();
+    let mut machines = Machines::new();
+    let scoped = machines.add("scoped", "AA:BB:CC:DD:EE:06".parse().unwrap(), Some("fe80::6".parse().unwrap()));
+    scoped.ipv6 = Some(waker::packet::ALL_NODES);
+    scoped.interface = Some("lo".to_string());
+    let global = machines.add("global", "AA:BB:CC:DD:EE:07".parse().unwrap(), None);
+    global.ipv6 = Some("2001:db8::7".parse().unwrap());
+    global.port = Some(7);
+    assert!(machines.wakeall_with(&transport, None).into_result().is_ok());
+
+    // Link-local addresses are scoped to the interface, which the socket is not bound to
+    let lo = waker::packet::interface_index("lo").unwrap();
+    let all_nodes = SocketAddrV6::new(waker::packet::ALL_NODES, 9, 0, lo);
+    let routes: Vec<Route> = transport.sent().into_iter().map(|(route, _)| route).collect();
+    assert_eq!(
+        vec![
+            Route::Udp { to: all_nodes.into(), from: "[::]:0".parse().unwrap(), device: None },
+            udp("[2001:db8::7]:7", "[::]:0", None),
+        ],
+        routes
+    );
+
+    // Bursts add the host's own addresses, link-local ones scoped like the destination
+    let transport = MockTransport::new();
+    machines.list[0].burst = Some(true);
+    machines.list[0].wake_with(&transport, None).unwrap();
+    let unicast = SocketAddrV6::new("fe80::6".parse().unwrap(), 9, 0, lo);
+    assert_eq!(Route::Udp { to: unicast.into(), from: "[::]:0".parse().unwrap(), device: None }, transport.sent()[2].0);
+
+    // Without an interface there is nothing to scope the destination to
+    machines.list[0].interface = None;
+    assert!(matches!(machines.list[0].wake_with(&transport, None), Err(WakerError::Config(_))));
+    machines.list[0].interface = Some("no-such-interface0".to_string());
+    assert!(matches!(machines.list[0].wake_with(&transport, None), Err(WakerError::Network(_))));
+}